@@ -0,0 +1,417 @@
+//! The applier module is responsible for executing operations read from an oplog against another
+//! MongoDB deployment, e.g. to maintain a live mirror.
+//!
+//! Operations are applied idempotently so that applying the same operation more than once (e.g.
+//! after resuming from a checkpoint) leaves the target in the same state.
+
+use bson::{Bson, Document};
+use mongodb::coll::Collection;
+use mongodb::coll::error::WriteException;
+use mongodb::coll::options::UpdateOptions;
+use mongodb::db::ThreadedDatabase;
+use mongodb::{Client, CommandType, ThreadedClient};
+
+use namespace;
+use {CommandKind, Error, Operation, Result, UpdateDescription};
+
+/// Error codes returned by commands that have already been applied.
+const IDEMPOTENT_ERROR_CODES: &[i32] = &[
+    26, // NamespaceNotFound, e.g. dropping a collection that does not exist.
+    27, // IndexNotFound, e.g. dropping an index that does not exist.
+    48, // NamespaceExists, e.g. creating a collection that already exists.
+];
+
+/// Applies operations to a MongoDB deployment.
+///
+/// Inserts and replacements are applied as upserts, updates in either the legacy or `$v: 2`
+/// format are translated into update modifiers, deletes of documents that do not exist are
+/// ignored and commands that have already been applied (e.g. creating a collection that exists)
+/// are ignored. No-ops and the `commitTransaction` and `abortTransaction` commands have no effect.
+///
+/// An update that truncates arrays as well as changing other fields is applied as one update per
+/// change, as MongoDB does not accept them in a single update document, so it is not atomic: a
+/// failure part-way through leaves the document partly updated. As each change is idempotent,
+/// applying the operation again completes it.
+///
+/// The operations in an `applyOps` command are applied in order but not atomically. As the
+/// operations of a multi-document transaction may be written before it commits, use
+/// `Transactions` to only apply transactions once they commit.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # extern crate mongodb;
+/// # extern crate oplog;
+/// use mongodb::{Client, ThreadedClient};
+/// use oplog::{Applier, Oplog, Transactions};
+///
+/// # fn main() {
+/// let source = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+/// let target = Client::connect("localhost", 27018).expect("Failed to connect to MongoDB.");
+/// let applier = Applier::new(&target);
+///
+/// if let Ok(oplog) = Oplog::new(&source) {
+///     for operation in Transactions::new(oplog) {
+///         applier.apply(&operation).expect("Failed to apply operation.");
+///     }
+/// }
+/// # }
+/// ```
+pub struct Applier {
+    target: Box<dyn Target>,
+}
+
+impl Applier {
+    /// Returns a new applier executing operations against the given MongoDB client.
+    pub fn new(client: &Client) -> Applier {
+        Applier { target: Box::new(client.clone()) }
+    }
+
+    /// Applies the given operation.
+    pub fn apply(&self, operation: &Operation) -> Result<()> {
+        match *operation {
+            Operation::Noop { .. } => Ok(()),
+            Operation::Insert { ref namespace, ref document, .. } => {
+                match document.get("_id") {
+                    Some(id) => {
+                        let filter = doc! { "_id" => (id.clone()) };
+
+                        self.target.replace_one(namespace, filter, document.clone())
+                    }
+                    None => self.target.insert_one(namespace, document.clone()),
+                }
+            }
+            Operation::Update { ref namespace, ref query, ref update, .. } => {
+                match UpdateDescription::new(update)? {
+                    UpdateDescription::Replacement(replacement) => {
+                        self.target.replace_one(namespace, query.clone(), replacement)
+                    }
+                    description => {
+                        for modifier in description.to_modifiers().unwrap_or_default() {
+                            self.target.update_one(namespace, query.clone(), modifier)?;
+                        }
+
+                        Ok(())
+                    }
+                }
+            }
+            Operation::Delete { ref namespace, ref query, .. } => {
+                self.target.delete_one(namespace, query.clone())
+            }
+            Operation::Command { ref namespace, ref command, .. } => {
+                let (db, _) = namespace::split(namespace).ok_or(Error::InvalidOperation)?;
+
+                match commands(db, command) {
+                    Some((db, command)) => self.run(&db, command),
+                    None => Ok(()),
+                }
+            }
+            Operation::ApplyOps { ref operations, .. } => {
+                for operation in operations {
+                    self.apply(operation)?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Runs the given command against the given database, ignoring errors from commands that
+    /// have already been applied.
+    fn run(&self, db: &str, command: Document) -> Result<()> {
+        let reply = self.target.command(db, command)?;
+
+        let ok = match reply.get("ok") {
+            Some(&Bson::FloatingPoint(ok)) => ok == 1.0,
+            Some(&Bson::I32(ok)) => ok == 1,
+            Some(&Bson::I64(ok)) => ok == 1,
+            _ => false,
+        };
+
+        if ok {
+            return Ok(());
+        }
+
+        if let Ok(code) = reply.get_i32("code") {
+            if IDEMPOTENT_ERROR_CODES.contains(&code) {
+                return Ok(());
+            }
+        }
+
+        let message = reply.get_str("errmsg").unwrap_or("command failed").to_owned();
+
+        Err(::mongodb::Error::OperationError(message).into())
+    }
+}
+
+/// The deployment against which an `Applier` executes operations.
+///
+/// This is implemented by `Client` and replaced in tests to apply operations in memory instead.
+trait Target: Send + Sync {
+    /// Replaces the document matching the given filter in the given namespace, inserting it if
+    /// there is none.
+    fn replace_one(&self, namespace: &str, filter: Document, replacement: Document) -> Result<()>;
+
+    /// Inserts the given document into the given namespace.
+    fn insert_one(&self, namespace: &str, document: Document) -> Result<()>;
+
+    /// Updates the document matching the given filter in the given namespace, if any.
+    fn update_one(&self, namespace: &str, filter: Document, update: Document) -> Result<()>;
+
+    /// Deletes the document matching the given filter in the given namespace, if any.
+    fn delete_one(&self, namespace: &str, filter: Document) -> Result<()>;
+
+    /// Runs the given command against the given database, returning its reply.
+    fn command(&self, db: &str, command: Document) -> Result<Document>;
+}
+
+impl Target for Client {
+    fn replace_one(&self, namespace: &str, filter: Document, replacement: Document) -> Result<()> {
+        let result = collection(self, namespace)?.replace_one(filter, replacement, upsert())?;
+
+        check(result.write_exception)
+    }
+
+    fn insert_one(&self, namespace: &str, document: Document) -> Result<()> {
+        check(collection(self, namespace)?.insert_one(document, None)?.write_exception)
+    }
+
+    fn update_one(&self, namespace: &str, filter: Document, update: Document) -> Result<()> {
+        check(collection(self, namespace)?.update_one(filter, update, None)?.write_exception)
+    }
+
+    fn delete_one(&self, namespace: &str, filter: Document) -> Result<()> {
+        check(collection(self, namespace)?.delete_one(filter, None)?.write_exception)
+    }
+
+    fn command(&self, db: &str, command: Document) -> Result<Document> {
+        Ok(self.db(db).command(command, CommandType::Suppressed, None)?)
+    }
+}
+
+/// Returns the database and command to run for the given command recorded in the oplog against
+/// the given database, if any.
+fn commands(db: &str, command: &Document) -> Option<(String, Document)> {
+    let name = command.keys().next()?;
+
+    match name.as_str() {
+        "commitTransaction" | "abortTransaction" | "startIndexBuild" | "abortIndexBuild" => None,
+        "renameCollection" => {
+            // The oplog records fields such as the UUID of any dropped target that the command
+            // itself does not accept.
+            match CommandKind::new(db, command) {
+                CommandKind::RenameCollection { from, to, drop_target } => {
+                    let rename = doc! {
+                        "renameCollection" => from,
+                        "to" => to,
+                        "dropTarget" => drop_target
+                    };
+
+                    Some(("admin".into(), rename))
+                }
+                _ => None,
+            }
+        }
+        "createIndexes" if !command.contains_key("indexes") => {
+            let mut spec = command.clone();
+            let collection = spec.remove("createIndexes")?;
+
+            Some((db.into(), doc! { "createIndexes" => collection, "indexes" => [spec] }))
+        }
+        "commitIndexBuild" => {
+            let collection = command.get("commitIndexBuild")?.clone();
+            let indexes = command.get("indexes")?.clone();
+
+            Some((db.into(), doc! { "createIndexes" => collection, "indexes" => indexes }))
+        }
+        _ => Some((db.into(), command.clone())),
+    }
+}
+
+/// Returns the collection with the given namespace.
+fn collection(client: &Client, namespace: &str) -> Result<Collection> {
+    let (db, collection) = namespace::split(namespace).ok_or(Error::InvalidOperation)?;
+
+    Ok(client.db(db).collection(collection))
+}
+
+/// Returns the options for an upsert.
+fn upsert() -> Option<UpdateOptions> {
+    let mut options = UpdateOptions::new();
+    options.upsert = Some(true);
+
+    Some(options)
+}
+
+/// Returns an error for the given write exception, if any.
+fn check(write_exception: Option<WriteException>) -> Result<()> {
+    match write_exception {
+        Some(exception) => Err(::mongodb::Error::WriteError(exception).into()),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    use bson::{Bson, Document};
+    use bson::spec::BinarySubtype;
+    use update;
+    use {DocumentStore, Error, MemoryDocumentStore, Operation, Result, UpdateDescription};
+    use super::{commands, Applier, Target};
+
+    /// A deployment applying writes to documents kept in memory and replying to each command
+    /// with the given replies in turn, then with success.
+    #[derive(Default)]
+    struct Memory {
+        store: Arc<Mutex<MemoryDocumentStore>>,
+        replies: Mutex<VecDeque<Document>>,
+    }
+
+    impl Target for Memory {
+        fn replace_one(&self,
+                       namespace: &str,
+                       filter: Document,
+                       replacement: Document)
+                       -> Result<()> {
+            let document = UpdateDescription::Replacement(replacement).apply_to(&filter)?;
+
+            self.store.lock().unwrap().put(namespace, document)
+        }
+
+        fn insert_one(&self, namespace: &str, document: Document) -> Result<()> {
+            self.store.lock().unwrap().put(namespace, document)
+        }
+
+        fn update_one(&self, namespace: &str, filter: Document, update: Document) -> Result<()> {
+            let id = filter.get("_id").ok_or(Error::InvalidOperation)?;
+            let mut store = self.store.lock().unwrap();
+
+            match store.get(namespace, id)? {
+                Some(document) => store.put(namespace, update::apply(&document, &update)?),
+                None => Ok(()),
+            }
+        }
+
+        fn delete_one(&self, namespace: &str, filter: Document) -> Result<()> {
+            let id = filter.get("_id").ok_or(Error::InvalidOperation)?;
+
+            self.store.lock().unwrap().remove(namespace, id)
+        }
+
+        fn command(&self, _db: &str, _command: Document) -> Result<Document> {
+            let reply = self.replies.lock().unwrap().pop_front();
+
+            Ok(reply.unwrap_or_else(|| doc! { "ok" => 1.0 }))
+        }
+    }
+
+    fn operation(op: &str, o: Document, o2: Option<Document>) -> Operation {
+        let mut document = doc! {
+            "ts" => (Bson::TimeStamp(1479561394 << 32)),
+            "op" => op,
+            "ns" => "foo.bar",
+            "o" => o
+        };
+        if let Some(o2) = o2 {
+            document.insert("o2", o2);
+        }
+
+        Operation::new(&document).unwrap()
+    }
+
+    #[test]
+    fn applier_upserts_inserts() {
+        let memory = Memory::default();
+        let store = memory.store.clone();
+        let applier = Applier { target: Box::new(memory) };
+
+        applier.apply(&operation("i", doc! { "_id" => 1, "a" => 1 }, None)).unwrap();
+        applier.apply(&operation("i", doc! { "_id" => 1, "a" => 2 }, None)).unwrap();
+        applier.apply(&operation("u",
+                                 doc! { "$v" => 2, "diff" => { "u" => { "a" => 3 } } },
+                                 Some(doc! { "_id" => 1 })))
+            .unwrap();
+
+        assert_eq!(store.lock().unwrap().documents("foo.bar"),
+                   vec![&doc! { "_id" => 1, "a" => 3 }]);
+    }
+
+    #[test]
+    fn applier_ignores_missing_deletes() {
+        let memory = Memory::default();
+        let store = memory.store.clone();
+        let applier = Applier { target: Box::new(memory) };
+
+        applier.apply(&operation("i", doc! { "_id" => 1 }, None)).unwrap();
+        applier.apply(&operation("d", doc! { "_id" => 1 }, None)).unwrap();
+        applier.apply(&operation("d", doc! { "_id" => 1 }, None)).unwrap();
+
+        assert!(store.lock().unwrap().documents("foo.bar").is_empty());
+    }
+
+    #[test]
+    fn applier_ignores_idempotent_command_errors() {
+        let memory = Memory::default();
+        memory.replies.lock().unwrap().extend(vec![
+            doc! { "ok" => 0.0, "code" => 48, "errmsg" => "collection already exists" },
+            doc! { "ok" => 0.0, "code" => 13, "errmsg" => "unauthorized" },
+        ]);
+        let applier = Applier { target: Box::new(memory) };
+        let create = Operation::new(&doc! {
+            "ts" => (Bson::TimeStamp(1479561394 << 32)),
+            "op" => "c",
+            "ns" => "foo.$cmd",
+            "o" => { "create" => "bar" }
+        }).unwrap();
+
+        assert!(applier.apply(&create).is_ok());
+        assert!(applier.apply(&create).is_err());
+        assert!(applier.apply(&create).is_ok());
+    }
+
+    #[test]
+    fn commands_translates_index_builds() {
+        let command = doc! {
+            "createIndexes" => "bar",
+            "v" => 2,
+            "key" => { "a" => 1 },
+            "name" => "a_1"
+        };
+
+        assert_eq!(commands("foo", &command),
+                   Some(("foo".into(),
+                         doc! {
+                             "createIndexes" => "bar",
+                             "indexes" => [{ "v" => 2, "key" => { "a" => 1 }, "name" => "a_1" }]
+                         })));
+        assert_eq!(commands("foo", &doc! { "commitTransaction" => 1 }), None);
+    }
+
+    #[test]
+    fn commands_translates_renames() {
+        let command = doc! {
+            "renameCollection" => "foo.bar",
+            "to" => "baz.quux",
+            "stayTemp" => false,
+            "dropTarget" => (Bson::Binary(BinarySubtype::Uuid, vec![0; 16]))
+        };
+
+        assert_eq!(commands("foo", &command),
+                   Some(("admin".into(),
+                         doc! {
+                             "renameCollection" => "foo.bar",
+                             "to" => "baz.quux",
+                             "dropTarget" => true
+                         })));
+        assert_eq!(commands("foo", &doc! { "renameCollection" => "foo.bar", "to" => "foo.baz" }),
+                   Some(("admin".into(),
+                         doc! {
+                             "renameCollection" => "foo.bar",
+                             "to" => "foo.baz",
+                             "dropTarget" => false
+                         })));
+    }
+}
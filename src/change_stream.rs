@@ -0,0 +1,426 @@
+//! The change stream module is responsible for reading operations from a MongoDB change stream
+//! rather than the oplog directly.
+//!
+//! Change streams do not require access to the `local` database and work on sharded clusters but
+//! only describe changes to documents, collections and databases. Each change event is converted
+//! into the equivalent `Operation` as it would appear in the oplog.
+
+use bson::{Bson, Document, ValueAccessError};
+use mongodb::common::{ReadMode, ReadPreference};
+use mongodb::cursor::Cursor;
+use mongodb::db::ThreadedDatabase;
+use mongodb::{Client, CommandType, ThreadedClient};
+
+use operation::{optional, uuid};
+use {Error, OpTime, Operation, OperationKind, Result, Transaction};
+
+/// Returns a cursor over all change events in the cluster after passing through the given
+/// pipeline stages.
+///
+/// The stream resumes after the given resume token if any, otherwise it starts at the given
+/// `OpTime` if any, otherwise it starts with the next change. If `update_lookup` is given, update
+/// events include the current version of the updated document.
+pub fn open_cursor(client: &Client,
+                   stages: &[Document],
+                   update_lookup: bool,
+                   resume_token: Option<&Document>,
+                   start_at: Option<OpTime>)
+                   -> Result<Cursor> {
+    let mut options = doc! { "allChangesForCluster" => true };
+
+    if update_lookup {
+        options.insert("fullDocument", "updateLookup");
+    }
+
+    if let Some(resume_token) = resume_token {
+        options.insert("resumeAfter", resume_token.clone());
+    } else if let Some(optime) = start_at {
+        options.insert("startAtOperationTime", optime);
+    }
+
+    let mut pipeline = vec![Bson::Document(doc! { "$changeStream" => options })];
+    pipeline.extend(stages.iter().cloned().map(Bson::Document));
+
+    let spec = doc! {
+        "aggregate" => 1,
+        "pipeline" => pipeline,
+        "cursor" => {}
+    };
+
+    Ok(client.db("admin")
+        .command_cursor(spec,
+                        CommandType::Aggregate,
+                        ReadPreference::new(ReadMode::Primary, None))?)
+}
+
+/// Returns the current cluster time, taken from the `operationTime` of a `ping` command, if the
+/// server reports one.
+///
+/// Unlike the top of the oplog, this is available on `mongos` and without access to the `local`
+/// database.
+pub fn cluster_time(client: &Client) -> Result<Option<OpTime>> {
+    let reply = client.db("admin").command(doc! { "ping" => 1 }, CommandType::Suppressed, None)?;
+
+    Ok(optional(reply.get_time_stamp("operationTime"))?.map(OpTime::from))
+}
+
+/// Returns the change event types equivalent to the given kind of operation.
+///
+/// No-ops and `applyOps` commands are never reported by change streams.
+pub fn operation_types(kind: OperationKind) -> &'static [&'static str] {
+    match kind {
+        OperationKind::Insert => &["insert"],
+        OperationKind::Update => &["update", "replace"],
+        OperationKind::Delete => &["delete"],
+        OperationKind::Command => &["drop", "rename", "dropDatabase"],
+        OperationKind::Noop | OperationKind::ApplyOps => &[],
+    }
+}
+
+/// Returns the operation equivalent to a change event.
+///
+/// Updates are converted into the `$set` and `$unset` modifiers they would be recorded as in the
+/// oplog, preceded by a `$push` of no elements with `$slice` for any truncated arrays, and
+/// replacements into updates with the full document.
+pub fn operation_from_event(event: &Document) -> Result<Operation> {
+    let operation_type = event.get_str("operationType")?;
+    let optime = OpTime::from(event.get_time_stamp("clusterTime")?);
+    let ns = event.get_document("ns")?;
+    let db = ns.get_str("db")?;
+    let transaction = transaction(event)?;
+    let uuid = uuid(event, "collectionUUID")?;
+    let wall = optional(event.get_utc_datetime("wallTime"))?.cloned();
+
+    match operation_type {
+        "insert" => {
+            Ok(Operation::Insert {
+                id: None,
+                optime,
+                timestamp: optime.to_datetime(),
+                term: None,
+                wall,
+                version: None,
+                transaction,
+                namespace: namespace(ns)?,
+                uuid,
+                document: event.get_document("fullDocument")?.to_owned(),
+            })
+        }
+        "update" => {
+            let description = event.get_document("updateDescription")?;
+            let mut update = Document::new();
+
+            if let Some(truncated_arrays) = optional(description.get_array("truncatedArrays"))? {
+                let mut push = Document::new();
+                for truncated_array in truncated_arrays {
+                    let truncated_array = match *truncated_array {
+                        Bson::Document(ref truncated_array) => truncated_array,
+                        _ => return Err(Error::InvalidOperation),
+                    };
+                    let length = match truncated_array.get("newSize") {
+                        Some(&Bson::I32(length)) => i64::from(length),
+                        Some(&Bson::I64(length)) => length,
+                        _ => return Err(Error::InvalidOperation),
+                    };
+
+                    push.insert(truncated_array.get_str("field")?,
+                                doc! { "$each" => [], "$slice" => length });
+                }
+                if !push.is_empty() {
+                    update.insert("$push", push);
+                }
+            }
+
+            let updated_fields = description.get_document("updatedFields")?;
+            if !updated_fields.is_empty() {
+                update.insert("$set", updated_fields.to_owned());
+            }
+
+            let removed_fields = description.get_array("removedFields")?;
+            if !removed_fields.is_empty() {
+                let mut unset = Document::new();
+                for field in removed_fields {
+                    match *field {
+                        Bson::String(ref field) => unset.insert(field.clone(), true),
+                        _ => return Err(Error::InvalidOperation),
+                    };
+                }
+                update.insert("$unset", unset);
+            }
+
+            Ok(Operation::Update {
+                id: None,
+                optime,
+                timestamp: optime.to_datetime(),
+                term: None,
+                wall,
+                version: None,
+                transaction,
+                namespace: namespace(ns)?,
+                uuid,
+                query: event.get_document("documentKey")?.to_owned(),
+                update,
+                full_document: nullable_document(event, "fullDocument")?,
+                pre_image_optime: None,
+                pre_image_term: None,
+                pre_image: None,
+            })
+        }
+        "replace" => {
+            Ok(Operation::Update {
+                id: None,
+                optime,
+                timestamp: optime.to_datetime(),
+                term: None,
+                wall,
+                version: None,
+                transaction,
+                namespace: namespace(ns)?,
+                uuid,
+                query: event.get_document("documentKey")?.to_owned(),
+                update: event.get_document("fullDocument")?.to_owned(),
+                full_document: Some(event.get_document("fullDocument")?.to_owned()),
+                pre_image_optime: None,
+                pre_image_term: None,
+                pre_image: None,
+            })
+        }
+        "delete" => {
+            Ok(Operation::Delete {
+                id: None,
+                optime,
+                timestamp: optime.to_datetime(),
+                term: None,
+                wall,
+                version: None,
+                transaction,
+                namespace: namespace(ns)?,
+                uuid,
+                query: event.get_document("documentKey")?.to_owned(),
+                pre_image_optime: None,
+                pre_image_term: None,
+                pre_image: None,
+            })
+        }
+        "drop" => {
+            Ok(Operation::Command {
+                id: None,
+                optime,
+                timestamp: optime.to_datetime(),
+                term: None,
+                wall,
+                version: None,
+                transaction,
+                namespace: format!("{}.$cmd", db),
+                uuid,
+                command: doc! { "drop" => (ns.get_str("coll")?) },
+            })
+        }
+        "rename" => {
+            Ok(Operation::Command {
+                id: None,
+                optime,
+                timestamp: optime.to_datetime(),
+                term: None,
+                wall,
+                version: None,
+                transaction,
+                namespace: format!("{}.$cmd", db),
+                uuid,
+                command: doc! {
+                    "renameCollection" => (namespace(ns)?),
+                    "to" => (namespace(event.get_document("to")?)?)
+                },
+            })
+        }
+        "dropDatabase" => {
+            Ok(Operation::Command {
+                id: None,
+                optime,
+                timestamp: optime.to_datetime(),
+                term: None,
+                wall,
+                version: None,
+                transaction,
+                namespace: format!("{}.$cmd", db),
+                uuid,
+                command: doc! { "dropDatabase" => 1 },
+            })
+        }
+        operation_type => Err(Error::UnknownOperation(operation_type.into())),
+    }
+}
+
+/// Returns the session and transaction of a change event, if any.
+///
+/// Change events do not describe their part in a transaction so only the session and transaction
+/// number are known.
+fn transaction(event: &Document) -> Result<Option<Transaction>> {
+    match optional(event.get_i64("txnNumber"))? {
+        Some(txn_number) => {
+            Ok(Some(Transaction {
+                session_id: event.get_document("lsid")?.to_owned(),
+                txn_number,
+                statement_id: None,
+                prev_optime: None,
+                prev_term: None,
+                state: None,
+            }))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Returns the document in the given field of a change event, treating null as missing.
+///
+/// Looked up documents are null if the document no longer exists.
+fn nullable_document(event: &Document, key: &str) -> Result<Option<Document>> {
+    match event.get(key) {
+        Some(Bson::Document(document)) => Ok(Some(document.clone())),
+        Some(&Bson::Null) | None => Ok(None),
+        Some(_) => Err(ValueAccessError::UnexpectedType.into()),
+    }
+}
+
+/// Returns the full namespace of a change event's `ns` or `to` document.
+fn namespace(ns: &Document) -> Result<String> {
+    Ok(format!("{}.{}", ns.get_str("db")?, ns.get_str("coll")?))
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::Bson;
+    use {Error, OpTime, Operation};
+    use super::operation_from_event;
+
+    #[test]
+    fn operation_from_event_converts_inserts() {
+        let event = doc! {
+            "_id" => { "_data" => "826" },
+            "operationType" => "insert",
+            "clusterTime" => (Bson::TimeStamp((1479561394 << 32) + 1)),
+            "ns" => { "db" => "foo", "coll" => "bar" },
+            "documentKey" => { "_id" => 1 },
+            "fullDocument" => { "_id" => 1, "foo" => "bar" }
+        };
+
+        assert_eq!(operation_from_event(&event).unwrap(),
+                   Operation::Insert {
+                       id: None,
+                       optime: OpTime::new(1479561394, 1),
+                       timestamp: OpTime::new(1479561394, 1).to_datetime(),
+                       term: None,
+                       wall: None,
+                       version: None,
+                       transaction: None,
+                       namespace: "foo.bar".into(),
+                       uuid: None,
+                       document: doc! { "_id" => 1, "foo" => "bar" },
+                   });
+    }
+
+    #[test]
+    fn operation_from_event_converts_updates() {
+        let event = doc! {
+            "_id" => { "_data" => "826" },
+            "operationType" => "update",
+            "clusterTime" => (Bson::TimeStamp(1479561394 << 32)),
+            "ns" => { "db" => "foo", "coll" => "bar" },
+            "documentKey" => { "_id" => 1 },
+            "updateDescription" => {
+                "updatedFields" => { "foo" => "baz" },
+                "removedFields" => ["quux"],
+                "truncatedArrays" => [{ "field" => "tags", "newSize" => 2 }]
+            }
+        };
+
+        assert_eq!(operation_from_event(&event).unwrap(),
+                   Operation::Update {
+                       id: None,
+                       optime: OpTime::new(1479561394, 0),
+                       timestamp: OpTime::new(1479561394, 0).to_datetime(),
+                       term: None,
+                       wall: None,
+                       version: None,
+                       transaction: None,
+                       namespace: "foo.bar".into(),
+                       uuid: None,
+                       query: doc! { "_id" => 1 },
+                       update: doc! {
+                           "$push" => { "tags" => { "$each" => [], "$slice" => 2i64 } },
+                           "$set" => { "foo" => "baz" },
+                           "$unset" => { "quux" => true }
+                       },
+                       full_document: None,
+                       pre_image_optime: None,
+                       pre_image_term: None,
+                       pre_image: None,
+                   });
+    }
+
+    #[test]
+    fn operation_from_event_converts_looked_up_updates() {
+        let event = doc! {
+            "_id" => { "_data" => "826" },
+            "operationType" => "update",
+            "clusterTime" => (Bson::TimeStamp(1479561394 << 32)),
+            "ns" => { "db" => "foo", "coll" => "bar" },
+            "documentKey" => { "_id" => 1 },
+            "updateDescription" => {
+                "updatedFields" => { "foo" => "baz" },
+                "removedFields" => []
+            },
+            "fullDocument" => { "_id" => 1, "foo" => "baz" }
+        };
+
+        match operation_from_event(&event).unwrap() {
+            Operation::Update { update, full_document, .. } => {
+                assert_eq!(update, doc! { "$set" => { "foo" => "baz" } });
+                assert_eq!(full_document, Some(doc! { "_id" => 1, "foo" => "baz" }));
+            }
+            _ => panic!("Expected update."),
+        }
+    }
+
+    #[test]
+    fn operation_from_event_converts_renames() {
+        let event = doc! {
+            "_id" => { "_data" => "826" },
+            "operationType" => "rename",
+            "clusterTime" => (Bson::TimeStamp(1479561394 << 32)),
+            "ns" => { "db" => "foo", "coll" => "bar" },
+            "to" => { "db" => "foo", "coll" => "baz" }
+        };
+
+        assert_eq!(operation_from_event(&event).unwrap(),
+                   Operation::Command {
+                       id: None,
+                       optime: OpTime::new(1479561394, 0),
+                       timestamp: OpTime::new(1479561394, 0).to_datetime(),
+                       term: None,
+                       wall: None,
+                       version: None,
+                       transaction: None,
+                       namespace: "foo.$cmd".into(),
+                       uuid: None,
+                       command: doc! { "renameCollection" => "foo.bar", "to" => "foo.baz" },
+                   });
+    }
+
+    #[test]
+    fn operation_from_event_returns_unknown_operations() {
+        let event = doc! {
+            "_id" => { "_data" => "826" },
+            "operationType" => "invalidate",
+            "clusterTime" => (Bson::TimeStamp(1479561394 << 32)),
+            "ns" => { "db" => "foo", "coll" => "bar" }
+        };
+
+        match operation_from_event(&event) {
+            Err(Error::UnknownOperation(operation_type)) => {
+                assert_eq!(operation_type, "invalidate")
+            }
+            _ => panic!("Expected unknown operation."),
+        }
+    }
+}
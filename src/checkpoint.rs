@@ -0,0 +1,222 @@
+//! The checkpoint module is responsible for persisting the position of the last operation
+//! processed from an oplog so that a consumer can resume from it after a restart.
+//!
+//! Checkpoints are committed periodically by an `Oplog` built with `OplogBuilder::checkpoint`
+//! according to a `CheckpointPolicy`. As an operation is only considered processed once the next
+//! one has been requested, this gives at-least-once delivery: after a restart, operations since
+//! the last checkpoint may be seen again but none will be skipped.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use bson::Bson;
+use mongodb::coll::Collection;
+use mongodb::coll::options::UpdateOptions;
+use mongodb::db::ThreadedDatabase;
+use mongodb::{Client, ThreadedClient};
+
+use {OpTime, Result};
+
+/// A store for the position of the last operation processed from an oplog.
+///
+/// Implement this trait to persist checkpoints somewhere other than the built-in
+/// `FileCheckpointStore` and `CollectionCheckpointStore`.
+pub trait CheckpointStore: Send + Sync {
+    /// Returns the position of the last committed operation, if any.
+    fn load(&self) -> Result<Option<OpTime>>;
+
+    /// Commits the position of the last processed operation.
+    fn save(&self, optime: OpTime) -> Result<()>;
+}
+
+/// A policy for how often an `Oplog` commits its position to a `CheckpointStore`.
+///
+/// A checkpoint is committed whenever either limit is reached.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CheckpointPolicy {
+    /// The number of operations to process between checkpoints, if any.
+    pub operations: Option<u32>,
+    /// The maximum time between checkpoints, if any.
+    pub interval: Option<Duration>,
+}
+
+impl Default for CheckpointPolicy {
+    /// Returns a policy committing every 1,000 operations or every 5 seconds.
+    fn default() -> CheckpointPolicy {
+        CheckpointPolicy {
+            operations: Some(1000),
+            interval: Some(Duration::from_secs(5)),
+        }
+    }
+}
+
+/// A `CheckpointStore` that keeps the position in a file on the local filesystem.
+///
+/// The file is replaced atomically on each commit so a crash during a commit cannot leave a
+/// partially written checkpoint behind.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # extern crate mongodb;
+/// # extern crate oplog;
+/// use mongodb::{Client, ThreadedClient};
+/// use oplog::{CheckpointPolicy, FileCheckpointStore, OplogBuilder};
+///
+/// # fn main() {
+/// let client = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+/// let store = FileCheckpointStore::new("oplog.checkpoint");
+///
+/// if let Ok(oplog) = OplogBuilder::new(&client)
+///                                 .checkpoint(store, CheckpointPolicy::default())
+///                                 .build() {
+///     // Do something with oplog.
+/// }
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct FileCheckpointStore {
+    path: PathBuf,
+}
+
+impl FileCheckpointStore {
+    /// Returns a new store using the file at the given path.
+    ///
+    /// The file does not need to exist until the first checkpoint is committed.
+    pub fn new<P: AsRef<Path>>(path: P) -> FileCheckpointStore {
+        FileCheckpointStore { path: path.as_ref().to_path_buf() }
+    }
+}
+
+impl CheckpointStore for FileCheckpointStore {
+    fn load(&self) -> Result<Option<OpTime>> {
+        let mut contents = String::new();
+
+        match File::open(&self.path) {
+            Ok(mut file) => file.read_to_string(&mut contents)?,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut parts = contents.split_whitespace().map(str::parse::<u32>);
+
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(seconds)), Some(Ok(increment)), None) => {
+                Ok(Some(OpTime::new(seconds, increment)))
+            }
+            _ => {
+                Err(io::Error::new(io::ErrorKind::InvalidData,
+                                   format!("invalid checkpoint in {}", self.path.display()))
+                    .into())
+            }
+        }
+    }
+
+    fn save(&self, optime: OpTime) -> Result<()> {
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+
+        {
+            let mut file = File::create(&temp)?;
+            writeln!(file, "{} {}", optime.seconds, optime.increment)?;
+            file.sync_all()?;
+        }
+
+        fs::rename(&temp, &self.path)?;
+
+        Ok(())
+    }
+}
+
+/// A `CheckpointStore` that keeps the position in a document in a MongoDB collection.
+///
+/// Each store is identified by an `_id` so that many consumers can share one collection.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # extern crate mongodb;
+/// # extern crate oplog;
+/// use mongodb::{Client, ThreadedClient};
+/// use oplog::{CheckpointPolicy, CollectionCheckpointStore, OplogBuilder};
+///
+/// # fn main() {
+/// let client = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+/// let store = CollectionCheckpointStore::new(&client, "app", "checkpoints", "search-indexer");
+///
+/// if let Ok(oplog) = OplogBuilder::new(&client)
+///                                 .checkpoint(store, CheckpointPolicy::default())
+///                                 .build() {
+///     // Do something with oplog.
+/// }
+/// # }
+/// ```
+pub struct CollectionCheckpointStore {
+    collection: Collection,
+    id: String,
+}
+
+impl CollectionCheckpointStore {
+    /// Returns a new store keeping its position in the document with the given `_id` in the given
+    /// database and collection.
+    pub fn new(client: &Client, db: &str, collection: &str, id: &str) -> CollectionCheckpointStore {
+        CollectionCheckpointStore {
+            collection: client.db(db).collection(collection),
+            id: id.into(),
+        }
+    }
+}
+
+impl CheckpointStore for CollectionCheckpointStore {
+    fn load(&self) -> Result<Option<OpTime>> {
+        match self.collection.find_one(Some(doc! { "_id" => (self.id.clone()) }), None)? {
+            Some(document) => Ok(Some(document.get_time_stamp("ts")?.into())),
+            None => Ok(None),
+        }
+    }
+
+    fn save(&self, optime: OpTime) -> Result<()> {
+        let mut options = UpdateOptions::new();
+        options.upsert = Some(true);
+
+        let filter = doc! { "_id" => (self.id.clone()) };
+        let replacement = doc! { "_id" => (self.id.clone()), "ts" => (Bson::from(optime)) };
+        let result = self.collection.replace_one(filter, replacement, Some(options))?;
+
+        match result.write_exception {
+            Some(exception) => Err(::mongodb::Error::WriteError(exception).into()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use {Error, OpTime};
+    use super::{CheckpointStore, FileCheckpointStore};
+
+    #[test]
+    fn file_checkpoint_store_round_trips_optimes() {
+        let path = env::temp_dir().join(format!("oplog-checkpoint-{}", process::id()));
+        let store = FileCheckpointStore::new(&path);
+
+        assert_eq!(store.load().unwrap(), None);
+
+        store.save(OpTime::new(1479561394, 2)).unwrap();
+        assert_eq!(store.load().unwrap(), Some(OpTime::new(1479561394, 2)));
+
+        fs::write(&path, "garbage").unwrap();
+        match store.load() {
+            Err(Error::Io(_)) => {}
+            _ => panic!("Expected invalid checkpoint."),
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+}
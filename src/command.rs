@@ -0,0 +1,274 @@
+//! The command module is responsible for decoding the commands recorded in the oplog (e.g.
+//! creating or dropping a collection) into specific `CommandKind` types.
+//!
+//! As new versions of MongoDB add commands and fields, any command that is not understood is kept
+//! as its original document rather than treated as an error.
+
+use bson::{Bson, Document};
+
+use operation::integer;
+
+/// A command recorded in the oplog, decoded from the `command` of an `Operation::Command`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CommandKind {
+    /// The creation of a collection or view.
+    Create {
+        /// The namespace of the new collection.
+        namespace: String,
+        /// The options of the new collection (e.g. `capped`, `validator` or `viewOn`).
+        options: Document,
+    },
+    /// The dropping of a collection.
+    Drop {
+        /// The namespace of the dropped collection.
+        namespace: String,
+    },
+    /// The dropping of a database.
+    DropDatabase {
+        /// The name of the dropped database.
+        database: String,
+    },
+    /// The renaming of a collection, possibly into another database.
+    RenameCollection {
+        /// The original namespace of the collection.
+        from: String,
+        /// The new namespace of the collection.
+        to: String,
+        /// Whether any existing collection at the new namespace was dropped.
+        ///
+        /// MongoDB 4.0 and later record the UUID of the dropped collection in place of `true`.
+        drop_target: bool,
+    },
+    /// The creation of one or more indexes.
+    ///
+    /// Indexes built by MongoDB 4.4 and later are recorded when their build commits.
+    CreateIndexes {
+        /// The namespace of the indexed collection.
+        namespace: String,
+        /// The specification of each index (e.g. its `key` and `name`).
+        indexes: Vec<Document>,
+    },
+    /// The dropping of one or more indexes.
+    DropIndexes {
+        /// The namespace of the indexed collection.
+        namespace: String,
+        /// The name of the dropped index, `*` for every index, an array of names or the key of
+        /// the dropped index.
+        index: Bson,
+    },
+    /// The modification of a collection's options.
+    CollMod {
+        /// The namespace of the modified collection.
+        namespace: String,
+        /// The modified options (e.g. `validator` or `index`).
+        options: Document,
+    },
+    /// The conversion of a collection into a capped collection.
+    ConvertToCapped {
+        /// The namespace of the converted collection.
+        namespace: String,
+        /// The maximum size of the capped collection in bytes.
+        size: i64,
+    },
+    /// Any other command, e.g. `commitTransaction`, or one missing the expected fields.
+    Other(Document),
+}
+
+impl CommandKind {
+    /// Returns the kind of the given command recorded in the oplog against the given database.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[macro_use]
+    /// # extern crate bson;
+    /// # extern crate oplog;
+    /// use oplog::CommandKind;
+    ///
+    /// # fn main() {
+    /// let command = doc! { "drop" => "bar" };
+    ///
+    /// assert_eq!(CommandKind::new("foo", &command),
+    ///            CommandKind::Drop { namespace: "foo.bar".into() });
+    /// # }
+    /// ```
+    pub fn new(database: &str, command: &Document) -> CommandKind {
+        CommandKind::decode(database, command)
+            .unwrap_or_else(|| CommandKind::Other(command.clone()))
+    }
+
+    /// Returns the kind of the given command if it is understood and has the expected fields.
+    fn decode(database: &str, command: &Document) -> Option<CommandKind> {
+        let name = command.keys().next()?;
+        let namespace = || command.get_str(name).ok().map(|collection| {
+            format!("{}.{}", database, collection)
+        });
+
+        match name.as_str() {
+            "create" => {
+                Some(CommandKind::Create {
+                    namespace: namespace()?,
+                    options: without(command, name),
+                })
+            }
+            "drop" => Some(CommandKind::Drop { namespace: namespace()? }),
+            "dropDatabase" => Some(CommandKind::DropDatabase { database: database.into() }),
+            "renameCollection" => {
+                Some(CommandKind::RenameCollection {
+                    from: command.get_str("renameCollection").ok()?.into(),
+                    to: command.get_str("to").ok()?.into(),
+                    drop_target: match command.get("dropTarget") {
+                        None | Some(Bson::Null) | Some(Bson::Boolean(false)) => false,
+                        Some(_) => true,
+                    },
+                })
+            }
+            "createIndexes" => {
+                let indexes = if command.contains_key("indexes") {
+                    documents(command.get_array("indexes").ok()?)?
+                } else {
+                    vec![without(command, name)]
+                };
+
+                Some(CommandKind::CreateIndexes {
+                    namespace: namespace()?,
+                    indexes,
+                })
+            }
+            "commitIndexBuild" => {
+                Some(CommandKind::CreateIndexes {
+                    namespace: namespace()?,
+                    indexes: documents(command.get_array("indexes").ok()?)?,
+                })
+            }
+            "dropIndexes" | "deleteIndexes" => {
+                Some(CommandKind::DropIndexes {
+                    namespace: namespace()?,
+                    index: command.get("index")?.clone(),
+                })
+            }
+            "collMod" => {
+                Some(CommandKind::CollMod {
+                    namespace: namespace()?,
+                    options: without(command, name),
+                })
+            }
+            "convertToCapped" => {
+                let size = match command.get("size") {
+                    Some(&Bson::FloatingPoint(size)) => size as i64,
+                    _ => integer(command, "size").ok()??,
+                };
+
+                Some(CommandKind::ConvertToCapped {
+                    namespace: namespace()?,
+                    size,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Returns the given command without the given field.
+fn without(command: &Document, key: &str) -> Document {
+    let mut options = command.clone();
+    options.remove(key);
+
+    options
+}
+
+/// Returns the given array if every element is a document.
+fn documents(array: &[Bson]) -> Option<Vec<Document>> {
+    array.iter()
+        .map(|value| match *value {
+            Bson::Document(ref document) => Some(document.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::Bson;
+    use bson::spec::BinarySubtype;
+    use super::CommandKind;
+
+    #[test]
+    fn command_kind_decodes_creates() {
+        let command = doc! { "create" => "bar", "capped" => true, "size" => 1024 };
+
+        assert_eq!(CommandKind::new("foo", &command),
+                   CommandKind::Create {
+                       namespace: "foo.bar".into(),
+                       options: doc! { "capped" => true, "size" => 1024 },
+                   });
+    }
+
+    #[test]
+    fn command_kind_decodes_renames() {
+        let command = doc! {
+            "renameCollection" => "foo.bar",
+            "to" => "baz.quux",
+            "dropTarget" => true
+        };
+
+        assert_eq!(CommandKind::new("foo", &command),
+                   CommandKind::RenameCollection {
+                       from: "foo.bar".into(),
+                       to: "baz.quux".into(),
+                       drop_target: true,
+                   });
+    }
+
+    #[test]
+    fn command_kind_decodes_renames_dropping_targets_by_uuid() {
+        let command = doc! {
+            "renameCollection" => "foo.bar",
+            "to" => "foo.baz",
+            "stayTemp" => false,
+            "dropTarget" => (Bson::Binary(BinarySubtype::Uuid, vec![0; 16]))
+        };
+
+        assert_eq!(CommandKind::new("foo", &command),
+                   CommandKind::RenameCollection {
+                       from: "foo.bar".into(),
+                       to: "foo.baz".into(),
+                       drop_target: true,
+                   });
+    }
+
+    #[test]
+    fn command_kind_decodes_index_builds() {
+        let create = doc! {
+            "createIndexes" => "bar",
+            "v" => 2,
+            "key" => { "a" => 1 },
+            "name" => "a_1"
+        };
+        let commit = doc! {
+            "commitIndexBuild" => "bar",
+            "indexBuildUUID" => "6d9f7ae1",
+            "indexes" => [{ "v" => 2, "key" => { "a" => 1 }, "name" => "a_1" }]
+        };
+        let expected = CommandKind::CreateIndexes {
+            namespace: "foo.bar".into(),
+            indexes: vec![doc! { "v" => 2, "key" => { "a" => 1 }, "name" => "a_1" }],
+        };
+
+        assert_eq!(CommandKind::new("foo", &create), expected);
+        assert_eq!(CommandKind::new("foo", &commit), expected);
+        assert_eq!(CommandKind::new("foo", &doc! { "dropIndexes" => "bar", "index" => "a_1" }),
+                   CommandKind::DropIndexes {
+                       namespace: "foo.bar".into(),
+                       index: Bson::String("a_1".into()),
+                   });
+    }
+
+    #[test]
+    fn command_kind_keeps_other_commands() {
+        assert_eq!(CommandKind::new("admin", &doc! { "commitTransaction" => 1 }),
+                   CommandKind::Other(doc! { "commitTransaction" => 1 }));
+        assert_eq!(CommandKind::new("foo", &doc! { "drop" => 1 }),
+                   CommandKind::Other(doc! { "drop" => 1 }));
+    }
+}
@@ -1,4 +1,6 @@
 #![warn(missing_docs)]
+// `Error` wraps the driver's own error so every `Result` in the crate is as large as it is.
+#![allow(clippy::result_large_err)]
 
 //! A library for iterating over a MongoDB replica set oplog.
 //!
@@ -47,21 +49,77 @@
 //! }
 //! # }
 //! ```
+//!
+//! Iterating over an `Oplog` directly ends at the first error. To handle errors yourself (e.g. to
+//! log a failed connection or skip an entry that could not be decoded), use `Oplog::try_iter`
+//! which yields a `Result` for every entry instead:
+//!
+//! ```rust,no_run
+//! # extern crate mongodb;
+//! # extern crate oplog;
+//! use mongodb::{Client, ThreadedClient};
+//! use oplog::Oplog;
+//!
+//! # fn main() {
+//! let client = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+//!
+//! if let Ok(mut oplog) = Oplog::new(&client) {
+//!     for result in oplog.try_iter() {
+//!         match result {
+//!             Ok(operation) => println!("{}", operation),
+//!             Err(err) => eprintln!("Error reading oplog: {}", err),
+//!         }
+//!     }
+//! }
+//! # }
+//! ```
 
 #[macro_use]
 extern crate bson;
 extern crate mongodb;
 extern crate chrono;
+extern crate uuid;
+#[cfg(feature = "async")]
+extern crate futures;
+#[cfg(test)]
+extern crate quickcheck;
 
 use std::error;
 use std::fmt;
+use std::io;
 use std::result;
 
-pub use operation::Operation;
-pub use oplog::{Oplog, OplogBuilder};
+pub use applier::Applier;
+pub use checkpoint::{CheckpointPolicy, CheckpointStore, CollectionCheckpointStore,
+                     FileCheckpointStore};
+pub use command::CommandKind;
+pub use materialized::{DocumentStore, MaterializedCollection, MemoryDocumentStore};
+pub use namespace::NamespaceMapper;
+pub use operation::{Operation, OperationKind};
+pub use oplog::{Backoff, Oplog, OplogBuilder, RawIter, Source, TryIter};
+pub use optime::OpTime;
+pub use pre_image::{PreImages, TryPreImages};
+pub use raw::RawOperation;
+pub use transaction::{Transaction, TransactionState, Transactions, TryTransactions};
+pub use update::UpdateDescription;
+#[cfg(feature = "async")]
+pub use stream::OplogStream;
 
+mod applier;
+mod change_stream;
+mod checkpoint;
+mod command;
+mod materialized;
+mod namespace;
 mod operation;
 mod oplog;
+mod optime;
+mod pre_image;
+mod raw;
+mod transaction;
+mod update;
+#[cfg(feature = "async")]
+mod stream;
 
 /// A type alias for convenience so we can fix the error to our own `Error` type.
 pub type Result<T> = result::Result<T, Error>;
@@ -79,15 +137,17 @@ pub enum Error {
     UnknownOperation(String),
     /// An error when converting an applyOps command with invalid documents.
     InvalidOperation,
+    /// An I/O error when reading or writing a checkpoint.
+    Io(io::Error),
 }
 
 impl error::Error for Error {
-    fn description(&self) -> &str {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match *self {
-            Error::Database(ref err) => err.description(),
-            Error::MissingField(ref err) => err.description(),
-            Error::UnknownOperation(_) => "unknown operation type",
-            Error::InvalidOperation => "invalid operation",
+            Error::Database(ref err) => Some(err),
+            Error::MissingField(ref err) => Some(err),
+            Error::Io(ref err) => Some(err),
+            Error::UnknownOperation(_) | Error::InvalidOperation => None,
         }
     }
 }
@@ -99,6 +159,7 @@ impl fmt::Display for Error {
             Error::MissingField(ref err) => err.fmt(f),
             Error::UnknownOperation(ref op) => write!(f, "Unknown operation type found: {}", op),
             Error::InvalidOperation => write!(f, "Invalid operation"),
+            Error::Io(ref err) => err.fmt(f),
         }
     }
 }
@@ -109,6 +170,12 @@ impl From<bson::ValueAccessError> for Error {
     }
 }
 
+impl From<io::Error> for Error {
+    fn from(original: io::Error) -> Error {
+        Error::Io(original)
+    }
+}
+
 impl From<mongodb::Error> for Error {
     fn from(original: mongodb::Error) -> Error {
         Error::Database(original)
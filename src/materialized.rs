@@ -0,0 +1,360 @@
+//! The materialized module is responsible for keeping a local copy of one or more collections
+//! up-to-date by applying operations read from the oplog.
+//!
+//! A copy is seeded by applying the inserts of an initial sync of each collection and then kept
+//! current by applying the operations written since the scan began. As every operation is applied
+//! idempotently, operations written during the scan may safely be applied to documents that
+//! already reflect them.
+
+use std::collections::{BTreeMap, HashMap};
+
+use bson::{Bson, Document};
+
+use namespace;
+use {CommandKind, Error, Oplog, OplogBuilder, Operation, Result, UpdateDescription};
+
+/// A store for the documents of a `MaterializedCollection`.
+///
+/// Implement this trait to keep documents somewhere other than the built-in
+/// `MemoryDocumentStore`, e.g. an embedded database or search index.
+pub trait DocumentStore {
+    /// Returns the document with the given `_id` in the given namespace, if any.
+    fn get(&self, namespace: &str, id: &Bson) -> Result<Option<Document>>;
+
+    /// Inserts or replaces the given document in the given namespace by its `_id`.
+    fn put(&mut self, namespace: &str, document: Document) -> Result<()>;
+
+    /// Removes the document with the given `_id` from the given namespace, if any.
+    fn remove(&mut self, namespace: &str, id: &Bson) -> Result<()>;
+
+    /// Removes every document from the given namespace.
+    fn clear(&mut self, namespace: &str) -> Result<()>;
+
+    /// Moves every document from one namespace to another, replacing any documents already in
+    /// the new namespace.
+    fn rename(&mut self, from: &str, to: &str) -> Result<()>;
+}
+
+/// A `DocumentStore` that keeps documents in memory.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MemoryDocumentStore {
+    namespaces: HashMap<String, BTreeMap<String, Document>>,
+}
+
+impl MemoryDocumentStore {
+    /// Returns a new, empty store.
+    pub fn new() -> MemoryDocumentStore {
+        MemoryDocumentStore::default()
+    }
+
+    /// Returns every document in the given namespace.
+    pub fn documents(&self, namespace: &str) -> Vec<&Document> {
+        self.namespaces
+            .get(namespace)
+            .map(|documents| documents.values().collect())
+            .unwrap_or_default()
+    }
+}
+
+impl DocumentStore for MemoryDocumentStore {
+    fn get(&self, namespace: &str, id: &Bson) -> Result<Option<Document>> {
+        Ok(self.namespaces
+               .get(namespace)
+               .and_then(|documents| documents.get(&id.to_string()))
+               .cloned())
+    }
+
+    fn put(&mut self, namespace: &str, document: Document) -> Result<()> {
+        let key = document.get("_id").ok_or(Error::InvalidOperation)?.to_string();
+        self.namespaces.entry(namespace.into()).or_default().insert(key, document);
+
+        Ok(())
+    }
+
+    fn remove(&mut self, namespace: &str, id: &Bson) -> Result<()> {
+        if let Some(documents) = self.namespaces.get_mut(namespace) {
+            documents.remove(&id.to_string());
+        }
+
+        Ok(())
+    }
+
+    fn clear(&mut self, namespace: &str) -> Result<()> {
+        self.namespaces.remove(namespace);
+
+        Ok(())
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+        match self.namespaces.remove(from) {
+            Some(documents) => self.namespaces.insert(to.into(), documents),
+            None => self.namespaces.remove(to),
+        };
+
+        Ok(())
+    }
+}
+
+/// A local copy of the documents in one or more collections, kept current from the oplog.
+///
+/// Dropping a collection or its database clears its copy. Renaming a copied collection moves its
+/// documents if the new namespace is also copied and otherwise clears them. As the documents of a
+/// collection renamed into a copied namespace from one that is not cannot be known from the oplog
+/// alone, the copy should be seeded again after such a rename.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # extern crate mongodb;
+/// # extern crate oplog;
+/// use mongodb::{Client, ThreadedClient};
+/// use oplog::{MaterializedCollection, OplogBuilder};
+///
+/// # fn main() {
+/// let client = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+/// let mut users = MaterializedCollection::new(&["app.users"]);
+///
+/// let oplog = users.seed(&mut OplogBuilder::new(&client)).expect("Failed to scan collection.");
+///
+/// for operation in oplog {
+///     users.apply(&operation).expect("Failed to apply operation.");
+/// }
+/// # }
+/// ```
+pub struct MaterializedCollection<S: DocumentStore = MemoryDocumentStore> {
+    namespaces: Vec<String>,
+    store: S,
+}
+
+impl MaterializedCollection<MemoryDocumentStore> {
+    /// Returns a new, empty copy of the given namespaces kept in memory.
+    pub fn new(namespaces: &[&str]) -> MaterializedCollection<MemoryDocumentStore> {
+        MaterializedCollection::with_store(namespaces, MemoryDocumentStore::new())
+    }
+}
+
+impl<S: DocumentStore> MaterializedCollection<S> {
+    /// Returns a new copy of the given namespaces kept in the given store.
+    pub fn with_store(namespaces: &[&str], store: S) -> MaterializedCollection<S> {
+        MaterializedCollection {
+            namespaces: namespaces.iter().map(|&namespace| namespace.into()).collect(),
+            store,
+        }
+    }
+
+    /// Returns an `Oplog` built by the given builder with an initial sync of each collection,
+    /// yielding an insert for each of their current documents followed by the operations on them
+    /// written since the scan began.
+    ///
+    /// The builder may set any other options, e.g. to resume after errors or commit checkpoints.
+    /// The copy is cleared before the scan but, as the scan is skipped if the oplog starts from
+    /// any other position (e.g. a committed checkpoint), it is kept if there is no scan so that a
+    /// copy in a persistent store can be resumed.
+    ///
+    /// Every operation yielded should be applied with `apply` to fill the copy and keep it
+    /// current.
+    pub fn seed(&mut self, builder: &mut OplogBuilder) -> Result<Oplog> {
+        for namespace in &self.namespaces {
+            let (database, _) = namespace::split(namespace).ok_or(Error::InvalidOperation)?;
+
+            builder.include_database(database).initial_sync(namespace);
+        }
+
+        let oplog = builder.build()?;
+        if oplog.is_syncing() {
+            for namespace in &self.namespaces {
+                self.store.clear(namespace)?;
+            }
+        }
+
+        Ok(oplog)
+    }
+
+    /// Applies the given operation to the copy, ignoring operations on other namespaces.
+    pub fn apply(&mut self, operation: &Operation) -> Result<()> {
+        match *operation {
+            Operation::Insert { ref namespace, ref document, .. } if self.includes(namespace) => {
+                self.store.put(namespace, document.clone())
+            }
+            Operation::Update { ref namespace, ref query, ref update, .. }
+                if self.includes(namespace) => {
+                let id = query.get("_id").ok_or(Error::InvalidOperation)?;
+
+                let document = match self.store.get(namespace, id)? {
+                    Some(document) => operation.apply_update(&document)?,
+                    None => {
+                        // Only a replacement describes the whole document if it is missing.
+                        match UpdateDescription::new(update) {
+                            Ok(replacement @ UpdateDescription::Replacement(_)) => {
+                                replacement.apply_to(&doc! { "_id" => (id.clone()) })?
+                            }
+                            _ => return Ok(()),
+                        }
+                    }
+                };
+
+                self.store.put(namespace, document)
+            }
+            Operation::Delete { ref namespace, ref query, .. } if self.includes(namespace) => {
+                self.store.remove(namespace, query.get("_id").ok_or(Error::InvalidOperation)?)
+            }
+            Operation::Command { .. } => {
+                let cleared = match operation.command_kind() {
+                    Some(CommandKind::Drop { namespace }) => vec![namespace],
+                    Some(CommandKind::RenameCollection { from, to, .. }) => {
+                        return match (self.includes(&from), self.includes(&to)) {
+                            (true, true) => self.store.rename(&from, &to),
+                            (true, false) => self.store.clear(&from),
+                            (false, true) => self.store.clear(&to),
+                            (false, false) => Ok(()),
+                        };
+                    }
+                    Some(CommandKind::DropDatabase { database }) => {
+                        let prefix = format!("{}.", database);
+
+                        self.namespaces
+                            .iter()
+                            .filter(|namespace| namespace.starts_with(&prefix))
+                            .cloned()
+                            .collect()
+                    }
+                    _ => Vec::new(),
+                };
+
+                for namespace in cleared {
+                    if self.includes(&namespace) {
+                        self.store.clear(&namespace)?;
+                    }
+                }
+
+                Ok(())
+            }
+            Operation::ApplyOps { ref operations, .. } => {
+                for operation in operations {
+                    self.apply(operation)?;
+                }
+
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the document with the given `_id` in the given namespace, if any.
+    pub fn get(&self, namespace: &str, id: &Bson) -> Result<Option<Document>> {
+        self.store.get(namespace, id)
+    }
+
+    /// Returns the store of the copy.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Returns whether the given namespace is copied.
+    fn includes(&self, namespace: &str) -> bool {
+        self.namespaces.iter().any(|included| included == namespace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::{Bson, Document};
+    use Operation;
+    use super::{DocumentStore, MaterializedCollection};
+
+    fn operation(op: &str, ns: &str, o: Document, o2: Option<Document>) -> Operation {
+        let mut document = doc! {
+            "ts" => (Bson::TimeStamp(1479561394 << 32)),
+            "op" => op,
+            "ns" => ns,
+            "o" => o
+        };
+        if let Some(o2) = o2 {
+            document.insert("o2", o2);
+        }
+
+        Operation::new(&document).unwrap()
+    }
+
+    #[test]
+    fn materialized_collection_applies_operations() {
+        let mut users = MaterializedCollection::new(&["app.users"]);
+
+        users.apply(&operation("i", "app.users", doc! { "_id" => 1, "name" => "Alice" }, None))
+            .unwrap();
+        users.apply(&operation("i", "app.users", doc! { "_id" => 2, "name" => "Bob" }, None))
+            .unwrap();
+        users.apply(&operation("i", "app.orders", doc! { "_id" => 3 }, None)).unwrap();
+        users.apply(&operation("u",
+                               "app.users",
+                               doc! { "$v" => 2, "diff" => { "u" => { "name" => "Carol" } } },
+                               Some(doc! { "_id" => 1 })))
+            .unwrap();
+        users.apply(&operation("d", "app.users", doc! { "_id" => 2 }, None)).unwrap();
+
+        assert_eq!(users.get("app.users", &Bson::I32(1)).unwrap(),
+                   Some(doc! { "_id" => 1, "name" => "Carol" }));
+        assert_eq!(users.get("app.users", &Bson::I32(2)).unwrap(), None);
+        assert_eq!(users.get("app.orders", &Bson::I32(3)).unwrap(), None);
+        assert_eq!(users.store().documents("app.users").len(), 1);
+    }
+
+    #[test]
+    fn materialized_collection_upserts_replacements() {
+        let mut users = MaterializedCollection::new(&["app.users"]);
+
+        users.apply(&operation("u",
+                               "app.users",
+                               doc! { "name" => "Alice" },
+                               Some(doc! { "_id" => 1 })))
+            .unwrap();
+        users.apply(&operation("u",
+                               "app.users",
+                               doc! { "$set" => { "name" => "Bob" } },
+                               Some(doc! { "_id" => 2 })))
+            .unwrap();
+
+        assert_eq!(users.get("app.users", &Bson::I32(1)).unwrap(),
+                   Some(doc! { "_id" => 1, "name" => "Alice" }));
+        assert_eq!(users.get("app.users", &Bson::I32(2)).unwrap(), None);
+    }
+
+    #[test]
+    fn materialized_collection_clears_dropped_collections() {
+        let mut users = MaterializedCollection::new(&["app.users", "app.orders"]);
+        users.apply(&operation("i", "app.users", doc! { "_id" => 1 }, None)).unwrap();
+        users.apply(&operation("i", "app.orders", doc! { "_id" => 1 }, None)).unwrap();
+
+        users.apply(&operation("c", "app.$cmd", doc! { "drop" => "users" }, None)).unwrap();
+
+        assert_eq!(users.get("app.users", &Bson::I32(1)).unwrap(), None);
+        assert!(users.get("app.orders", &Bson::I32(1)).unwrap().is_some());
+
+        users.apply(&operation("c", "app.$cmd", doc! { "dropDatabase" => 1 }, None)).unwrap();
+
+        assert_eq!(users.store.get("app.orders", &Bson::I32(1)).unwrap(), None);
+    }
+
+    #[test]
+    fn materialized_collection_moves_renamed_collections() {
+        let mut users = MaterializedCollection::new(&["app.users", "app.people"]);
+        users.apply(&operation("i", "app.users", doc! { "_id" => 1 }, None)).unwrap();
+
+        users.apply(&operation("c",
+                               "app.$cmd",
+                               doc! { "renameCollection" => "app.users", "to" => "app.people" },
+                               None))
+            .unwrap();
+
+        assert_eq!(users.get("app.users", &Bson::I32(1)).unwrap(), None);
+        assert_eq!(users.get("app.people", &Bson::I32(1)).unwrap(), Some(doc! { "_id" => 1 }));
+
+        users.apply(&operation("c",
+                               "app.$cmd",
+                               doc! { "renameCollection" => "app.people", "to" => "app.old" },
+                               None))
+            .unwrap();
+
+        assert_eq!(users.get("app.people", &Bson::I32(1)).unwrap(), None);
+    }
+}
@@ -0,0 +1,448 @@
+//! The namespace module is responsible for describing which MongoDB namespaces (i.e. a database
+//! and collection such as `foo.bar`) an oplog should include or exclude and how they should be
+//! renamed.
+
+use bson::{Bson, Document};
+
+use Operation;
+
+/// Commands recorded in the oplog whose value is the name of the collection they affect.
+const COLLECTION_COMMANDS: &[&str] = &["create",
+                                       "drop",
+                                       "collMod",
+                                       "convertToCapped",
+                                       "emptycapped",
+                                       "createIndexes",
+                                       "dropIndexes",
+                                       "deleteIndexes",
+                                       "startIndexBuild",
+                                       "commitIndexBuild",
+                                       "abortIndexBuild"];
+
+/// A pattern matching one or more namespaces.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NamespacePattern {
+    /// A single namespace such as `foo.bar`.
+    Exact(String),
+    /// Every namespace in a database such as `foo`, including its commands.
+    Database(String),
+    /// A glob pattern such as `foo.*` where `*` matches any sequence of characters.
+    Glob(String),
+    /// A regular expression as understood by the MongoDB server.
+    Regex(String),
+}
+
+impl NamespacePattern {
+    /// Returns a pattern for the given namespace, treating it as a glob if it contains a `*`.
+    pub fn new(namespace: &str) -> NamespacePattern {
+        if namespace.contains('*') {
+            NamespacePattern::Glob(namespace.into())
+        } else {
+            NamespacePattern::Exact(namespace.into())
+        }
+    }
+
+    /// Returns the BSON value matching this pattern in a server-side query.
+    pub fn to_bson(&self) -> Bson {
+        match *self {
+            NamespacePattern::Exact(ref namespace) => Bson::String(namespace.clone()),
+            NamespacePattern::Database(ref database) => {
+                Bson::RegExp(format!("^{}\\.", escape(database)), String::new())
+            }
+            NamespacePattern::Glob(ref glob) => {
+                let pattern = glob.split('*').map(escape).collect::<Vec<_>>().join(".*");
+
+                Bson::RegExp(format!("^{}$", pattern), String::new())
+            }
+            NamespacePattern::Regex(ref regex) => Bson::RegExp(regex.clone(), String::new()),
+        }
+    }
+}
+
+/// Rewrites the namespaces of operations, e.g. to replay operations from `prod.users` into
+/// `staging.users`.
+///
+/// Rules are tried in the order they were added and the first matching rule renames the
+/// namespace. Namespaces matching no rule are left unchanged.
+///
+/// The operations of an `applyOps` command are renamed as well as the collections named by
+/// commands such as `create`, `drop` and `renameCollection`.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # extern crate mongodb;
+/// # extern crate oplog;
+/// use mongodb::{Client, ThreadedClient};
+/// use oplog::{Applier, NamespaceMapper, Oplog};
+///
+/// # fn main() {
+/// let source = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+/// let target = Client::connect("localhost", 27018).expect("Failed to connect to MongoDB.");
+/// let applier = Applier::new(&target);
+///
+/// let mut mapper = NamespaceMapper::new();
+/// mapper.rename_database("prod", "staging").rename_pattern("*.accounts", "*.users");
+///
+/// if let Ok(oplog) = Oplog::new(&source) {
+///     for operation in oplog {
+///         applier.apply(&mapper.map(operation)).expect("Failed to apply operation.");
+///     }
+/// }
+/// # }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NamespaceMapper {
+    rules: Vec<Rule>,
+}
+
+/// A rule renaming one or more namespaces.
+#[derive(Clone, Debug, PartialEq)]
+enum Rule {
+    /// Renames a single namespace.
+    Exact(String, String),
+    /// Renames a database, keeping the names of its collections.
+    Database(String, String),
+    /// Renames namespaces matching a glob pattern.
+    Glob(String, String),
+}
+
+impl NamespaceMapper {
+    /// Returns a new mapper without any rules.
+    pub fn new() -> NamespaceMapper {
+        NamespaceMapper::default()
+    }
+
+    /// Renames the given namespace (e.g. `prod.users`) to another.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use oplog::NamespaceMapper;
+    ///
+    /// let mut mapper = NamespaceMapper::new();
+    /// mapper.rename_namespace("prod.users", "staging.users");
+    ///
+    /// assert_eq!(mapper.namespace("prod.users"), "staging.users");
+    /// assert_eq!(mapper.namespace("prod.orders"), "prod.orders");
+    /// ```
+    pub fn rename_namespace(&mut self, from: &str, to: &str) -> &mut NamespaceMapper {
+        self.rules.push(Rule::Exact(from.into(), to.into()));
+        self
+    }
+
+    /// Renames the given database, including its commands, keeping the names of its collections.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use oplog::NamespaceMapper;
+    ///
+    /// let mut mapper = NamespaceMapper::new();
+    /// mapper.rename_database("prod", "staging");
+    ///
+    /// assert_eq!(mapper.namespace("prod.users"), "staging.users");
+    /// assert_eq!(mapper.namespace("prod.$cmd"), "staging.$cmd");
+    /// ```
+    pub fn rename_database(&mut self, from: &str, to: &str) -> &mut NamespaceMapper {
+        self.rules.push(Rule::Database(from.into(), to.into()));
+        self
+    }
+
+    /// Renames namespaces matching the given glob pattern where `*` matches any sequence of
+    /// characters. Each `*` in the replacement is substituted with the characters matched by the
+    /// `*` in the same position of the pattern.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use oplog::NamespaceMapper;
+    ///
+    /// let mut mapper = NamespaceMapper::new();
+    /// mapper.rename_pattern("shard_*.accounts", "shard_*.users");
+    ///
+    /// assert_eq!(mapper.namespace("shard_1.accounts"), "shard_1.users");
+    /// ```
+    pub fn rename_pattern(&mut self, pattern: &str, replacement: &str) -> &mut NamespaceMapper {
+        self.rules.push(Rule::Glob(pattern.into(), replacement.into()));
+        self
+    }
+
+    /// Returns the given namespace renamed by the first matching rule, if any.
+    pub fn namespace(&self, namespace: &str) -> String {
+        self.rules
+            .iter()
+            .filter_map(|rule| rule.rename(namespace))
+            .next()
+            .unwrap_or_else(|| namespace.into())
+    }
+
+    /// Returns the given operation with its namespaces renamed.
+    pub fn map(&self, mut operation: Operation) -> Operation {
+        match operation {
+            Operation::Noop { .. } => {}
+            Operation::Insert { ref mut namespace, .. } |
+            Operation::Update { ref mut namespace, .. } |
+            Operation::Delete { ref mut namespace, .. } => *namespace = self.namespace(namespace),
+            Operation::Command { ref mut namespace, ref mut command, .. } => {
+                self.map_command(namespace, command)
+            }
+            Operation::ApplyOps { ref mut namespace, ref mut operations, .. } => {
+                *namespace = self.namespace(namespace);
+                *operations = operations.drain(..).map(|operation| self.map(operation)).collect();
+            }
+        }
+
+        operation
+    }
+
+    /// Renames the namespace of a command and any collections it names.
+    fn map_command(&self, namespace: &mut String, command: &mut Document) {
+        let name = match command.keys().next() {
+            Some(name) => name.clone(),
+            None => return,
+        };
+
+        if name == "renameCollection" {
+            let mut database = None;
+
+            for key in &["renameCollection", "to"] {
+                if let Some(&mut Bson::String(ref mut target)) = command.get_mut(key) {
+                    *target = self.namespace(target);
+                    database = database.or_else(|| split(target).map(|(db, _)| db.to_owned()));
+                }
+            }
+
+            if let Some(database) = database {
+                *namespace = format!("{}.$cmd", database);
+            }
+
+            return;
+        }
+
+        let collection = match command.get_str(&name) {
+            Ok(collection) if COLLECTION_COMMANDS.contains(&name.as_str()) => {
+                split(namespace).map(|(db, _)| self.namespace(&format!("{}.{}", db, collection)))
+            }
+            _ => None,
+        };
+
+        match collection.as_ref().and_then(|collection| split(collection)) {
+            Some((db, collection)) => {
+                if let Some(value) = command.get_mut(&name) {
+                    *value = Bson::String(collection.into());
+                }
+                *namespace = format!("{}.$cmd", db);
+            }
+            None => *namespace = self.namespace(namespace),
+        }
+    }
+}
+
+impl Rule {
+    /// Returns the given namespace renamed by this rule if it matches.
+    fn rename(&self, namespace: &str) -> Option<String> {
+        match *self {
+            Rule::Exact(ref from, ref to) => {
+                if namespace == from {
+                    Some(to.clone())
+                } else {
+                    None
+                }
+            }
+            Rule::Database(ref from, ref to) => {
+                match split(namespace) {
+                    Some((db, collection)) if db == from => Some(format!("{}.{}", to, collection)),
+                    _ => None,
+                }
+            }
+            Rule::Glob(ref pattern, ref replacement) => {
+                let captures = glob(pattern, namespace)?;
+                let mut renamed = String::new();
+
+                for (i, part) in replacement.split('*').enumerate() {
+                    if i > 0 {
+                        renamed.push_str(captures.get(i - 1).cloned().unwrap_or(""));
+                    }
+                    renamed.push_str(part);
+                }
+
+                Some(renamed)
+            }
+        }
+    }
+}
+
+/// Returns the characters matched by each `*` if the given glob pattern matches the given text.
+fn glob<'a>(pattern: &str, text: &'a str) -> Option<Vec<&'a str>> {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    let (first, rest) = parts.split_first()?;
+
+    if !text.starts_with(first) {
+        return None;
+    }
+
+    let (last, middle) = match rest.split_last() {
+        Some(parts) => parts,
+        None => return if text == pattern { Some(Vec::new()) } else { None },
+    };
+
+    if text.len() < first.len() + last.len() || !text.ends_with(last) {
+        return None;
+    }
+
+    let end = text.len() - last.len();
+    let mut position = first.len();
+    let mut captures = Vec::with_capacity(rest.len());
+
+    for part in middle {
+        let index = text[position..end].find(part)?;
+        captures.push(&text[position..position + index]);
+        position += index + part.len();
+    }
+    captures.push(&text[position..end]);
+
+    Some(captures)
+}
+
+/// Returns the database and collection of the given namespace.
+pub(crate) fn split(namespace: &str) -> Option<(&str, &str)> {
+    namespace.find('.').map(|index| (&namespace[..index], &namespace[index + 1..]))
+}
+
+/// Escapes any characters with a special meaning in a regular expression.
+fn escape(literal: &str) -> String {
+    let mut escaped = String::with_capacity(literal.len());
+
+    for c in literal.chars() {
+        if "\\.+*?()|[]{}^$#&-~".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::Bson;
+    use Operation;
+    use super::{NamespaceMapper, NamespacePattern};
+
+    #[test]
+    fn namespace_pattern_matches_exact_namespaces() {
+        assert_eq!(NamespacePattern::new("foo.bar").to_bson(), Bson::String("foo.bar".into()));
+    }
+
+    #[test]
+    fn namespace_pattern_matches_databases() {
+        assert_eq!(NamespacePattern::Database("foo".into()).to_bson(),
+                   Bson::RegExp("^foo\\.".into(), "".into()));
+    }
+
+    #[test]
+    fn namespace_pattern_converts_globs() {
+        assert_eq!(NamespacePattern::new("foo.*").to_bson(),
+                   Bson::RegExp("^foo\\..*$".into(), "".into()));
+        assert_eq!(NamespacePattern::new("*.users$").to_bson(),
+                   Bson::RegExp("^.*\\.users\\$$".into(), "".into()));
+    }
+
+    #[test]
+    fn namespace_mapper_renames_globs() {
+        let mut mapper = NamespaceMapper::new();
+        mapper.rename_pattern("*.accounts_*", "archive.*_*")
+            .rename_namespace("foo.bar", "baz.quux");
+
+        assert_eq!(mapper.namespace("prod.accounts_2019"), "archive.prod_2019");
+        assert_eq!(mapper.namespace("prod.accounts"), "prod.accounts");
+        assert_eq!(mapper.namespace("foo.bar"), "baz.quux");
+        assert_eq!(mapper.namespace("foo.bar.baz"), "foo.bar.baz");
+    }
+
+    #[test]
+    fn namespace_mapper_renames_commands() {
+        let mut mapper = NamespaceMapper::new();
+        mapper.rename_namespace("prod.users", "staging.people").rename_database("prod", "test");
+
+        let drop = Operation::new(&doc! {
+            "ts" => (Bson::TimeStamp(1479561394 << 32)),
+            "op" => "c",
+            "ns" => "prod.$cmd",
+            "o" => { "drop" => "users" }
+        }).unwrap();
+        let rename = Operation::new(&doc! {
+            "ts" => (Bson::TimeStamp(1479561394 << 32)),
+            "op" => "c",
+            "ns" => "prod.$cmd",
+            "o" => {
+                "renameCollection" => "prod.users",
+                "to" => "prod.accounts",
+                "dropTarget" => false
+            }
+        }).unwrap();
+        let drop_database = Operation::new(&doc! {
+            "ts" => (Bson::TimeStamp(1479561394 << 32)),
+            "op" => "c",
+            "ns" => "prod.$cmd",
+            "o" => { "dropDatabase" => 1 }
+        }).unwrap();
+
+        match mapper.map(drop) {
+            Operation::Command { namespace, command, .. } => {
+                assert_eq!(namespace, "staging.$cmd");
+                assert_eq!(command, doc! { "drop" => "people" });
+            }
+            _ => panic!("Expected command."),
+        }
+        match mapper.map(rename) {
+            Operation::Command { namespace, command, .. } => {
+                assert_eq!(namespace, "staging.$cmd");
+                assert_eq!(command,
+                           doc! {
+                               "renameCollection" => "staging.people",
+                               "to" => "test.accounts",
+                               "dropTarget" => false
+                           });
+            }
+            _ => panic!("Expected command."),
+        }
+        match mapper.map(drop_database) {
+            Operation::Command { namespace, .. } => assert_eq!(namespace, "test.$cmd"),
+            _ => panic!("Expected command."),
+        }
+    }
+
+    #[test]
+    fn namespace_mapper_renames_apply_ops() {
+        let mut mapper = NamespaceMapper::new();
+        mapper.rename_database("prod", "staging");
+
+        let apply_ops = Operation::new(&doc! {
+            "ts" => (Bson::TimeStamp(1479561394 << 32)),
+            "op" => "c",
+            "ns" => "admin.$cmd",
+            "o" => {
+                "applyOps" => [{
+                    "op" => "i",
+                    "ns" => "prod.users",
+                    "o" => { "_id" => 1 }
+                }]
+            }
+        }).unwrap();
+
+        match mapper.map(apply_ops) {
+            Operation::ApplyOps { namespace, operations, .. } => {
+                assert_eq!(namespace, "admin.$cmd");
+                match operations[0] {
+                    Operation::Insert { ref namespace, .. } => {
+                        assert_eq!(namespace, "staging.users")
+                    }
+                    _ => panic!("Expected insert."),
+                }
+            }
+            _ => panic!("Expected applyOps."),
+        }
+    }
+}
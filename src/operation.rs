@@ -1,16 +1,19 @@
 //! The operation module is responsible for converting MongoDB BSON documents into specific
 //! `Operation` types, one for each type of document stored in the MongoDB oplog. As much as
-//! possible, we convert BSON types into more typical Rust types (e.g. BSON timestamps into UTC
-//! datetimes).
+//! possible, we convert BSON types into more typical Rust types (e.g. BSON timestamps into
+//! `OpTime`s and UTC datetimes).
 //!
 //! As we accept _any_ document, it may not be a valid operation so wrap any conversions in a
 //! `Result`.
 
 use std::fmt;
 
-use bson::{Bson, Document};
-use chrono::{DateTime, UTC, TimeZone};
-use {Error, Result};
+use bson::{Bson, Document, ValueAccessError, ValueAccessResult};
+use bson::spec::BinarySubtype;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+use update;
+use {CommandKind, Error, OpTime, Result, Transaction, TransactionState};
 
 /// A MongoDB oplog operation.
 #[derive(Clone, Debug, PartialEq)]
@@ -18,71 +21,256 @@ pub enum Operation {
     /// A no-op as inserted periodically by MongoDB or used to initiate new replica sets.
     Noop {
         /// A unique identifier for this operation.
-        id: i64,
+        ///
+        /// This is only present on oplog entries written by MongoDB versions prior to 4.2.
+        id: Option<i64>,
+        /// The position of the operation in the oplog.
+        optime: OpTime,
         /// The time of the operation.
-        timestamp: DateTime<UTC>,
-        /// The message associated with this operation.
+        timestamp: DateTime<Utc>,
+        /// The election term of the primary that wrote the operation (`t`), if any.
+        ///
+        /// This is only present on oplog entries written by replica sets using protocol version 1.
+        term: Option<i64>,
+        /// The wall clock time of the primary when it wrote the operation (`wall`), if any.
+        ///
+        /// This is only present on oplog entries written by MongoDB 4.0 and later.
+        wall: Option<DateTime<Utc>>,
+        /// The version of the oplog entry format (`v`), if any.
+        version: Option<i64>,
+        /// The session and transaction the operation was written in, if any.
+        transaction: Option<Transaction>,
+        /// The message associated with this operation, or empty if it has none.
         message: String,
+        /// The object of the operation (`o`), including its message and any other fields.
+        object: Document,
     },
     /// An insert of a document into a specific database and collection.
     Insert {
         /// A unique identifier for this operation.
-        id: i64,
+        ///
+        /// This is only present on oplog entries written by MongoDB versions prior to 4.2.
+        id: Option<i64>,
+        /// The position of the operation in the oplog.
+        optime: OpTime,
         /// The time of the operation.
-        timestamp: DateTime<UTC>,
+        timestamp: DateTime<Utc>,
+        /// The election term of the primary that wrote the operation (`t`), if any.
+        ///
+        /// This is only present on oplog entries written by replica sets using protocol version 1.
+        term: Option<i64>,
+        /// The wall clock time of the primary when it wrote the operation (`wall`), if any.
+        ///
+        /// This is only present on oplog entries written by MongoDB 4.0 and later.
+        wall: Option<DateTime<Utc>>,
+        /// The version of the oplog entry format (`v`), if any.
+        version: Option<i64>,
+        /// The session and transaction the operation was written in, if any.
+        transaction: Option<Transaction>,
         /// The full namespace of the operation including its database and collection.
         namespace: String,
+        /// The UUID of the collection (`ui`), if any.
+        ///
+        /// This is only present on oplog entries written by MongoDB 3.6 and later and, unlike the
+        /// namespace, is unchanged when a collection is renamed.
+        uuid: Option<Uuid>,
         /// The BSON document inserted into the namespace.
         document: Document,
     },
     /// An update of a document in a specific database and collection matching a given query.
     Update {
         /// A unique identifier for this operation.
-        id: i64,
+        ///
+        /// This is only present on oplog entries written by MongoDB versions prior to 4.2.
+        id: Option<i64>,
+        /// The position of the operation in the oplog.
+        optime: OpTime,
         /// The time of the operation.
-        timestamp: DateTime<UTC>,
+        timestamp: DateTime<Utc>,
+        /// The election term of the primary that wrote the operation (`t`), if any.
+        ///
+        /// This is only present on oplog entries written by replica sets using protocol version 1.
+        term: Option<i64>,
+        /// The wall clock time of the primary when it wrote the operation (`wall`), if any.
+        ///
+        /// This is only present on oplog entries written by MongoDB 4.0 and later.
+        wall: Option<DateTime<Utc>>,
+        /// The version of the oplog entry format (`v`), if any.
+        version: Option<i64>,
+        /// The session and transaction the operation was written in, if any.
+        transaction: Option<Transaction>,
         /// The full namespace of the operation including its database and collection.
         namespace: String,
+        /// The UUID of the collection (`ui`), if any.
+        ///
+        /// This is only present on oplog entries written by MongoDB 3.6 and later and, unlike the
+        /// namespace, is unchanged when a collection is renamed.
+        uuid: Option<Uuid>,
         /// The BSON selection criteria for the update.
         query: Document,
         /// The BSON update applied in this operation.
         update: Document,
+        /// The current version of the updated document, if it was looked up.
+        ///
+        /// This is only present if the oplog was built with `OplogBuilder::update_lookup` or the
+        /// update is a replacement read from a change stream, and is not part of the oplog entry.
+        full_document: Option<Document>,
+        /// The position of the no-op recording the document before the update
+        /// (`preImageOpTime`), if any.
+        ///
+        /// This is only present on oplog entries written by MongoDB 4.4 and 5.0 for collections
+        /// with `recordPreImages` enabled.
+        pre_image_optime: Option<OpTime>,
+        /// The election term recorded with `pre_image_optime` (`preImageOpTime.t`), if any.
+        pre_image_term: Option<i64>,
+        /// The version of the updated document before the update, if known.
+        ///
+        /// This is only present if read through `PreImages`, and is not part of the oplog entry.
+        pre_image: Option<Document>,
     },
     /// The deletion of a document in a specific database and collection matching a given query.
     Delete {
         /// A unique identifier for this operation.
-        id: i64,
+        ///
+        /// This is only present on oplog entries written by MongoDB versions prior to 4.2.
+        id: Option<i64>,
+        /// The position of the operation in the oplog.
+        optime: OpTime,
         /// The time of the operation.
-        timestamp: DateTime<UTC>,
+        timestamp: DateTime<Utc>,
+        /// The election term of the primary that wrote the operation (`t`), if any.
+        ///
+        /// This is only present on oplog entries written by replica sets using protocol version 1.
+        term: Option<i64>,
+        /// The wall clock time of the primary when it wrote the operation (`wall`), if any.
+        ///
+        /// This is only present on oplog entries written by MongoDB 4.0 and later.
+        wall: Option<DateTime<Utc>>,
+        /// The version of the oplog entry format (`v`), if any.
+        version: Option<i64>,
+        /// The session and transaction the operation was written in, if any.
+        transaction: Option<Transaction>,
         /// The full namespace of the operation including its database and collection.
         namespace: String,
+        /// The UUID of the collection (`ui`), if any.
+        ///
+        /// This is only present on oplog entries written by MongoDB 3.6 and later and, unlike the
+        /// namespace, is unchanged when a collection is renamed.
+        uuid: Option<Uuid>,
         /// The BSON selection criteria for the delete.
         query: Document,
+        /// The position of the no-op recording the document before the delete
+        /// (`preImageOpTime`), if any.
+        ///
+        /// This is only present on oplog entries written by MongoDB 4.4 and 5.0 for collections
+        /// with `recordPreImages` enabled.
+        pre_image_optime: Option<OpTime>,
+        /// The election term recorded with `pre_image_optime` (`preImageOpTime.t`), if any.
+        pre_image_term: Option<i64>,
+        /// The deleted document, if known.
+        ///
+        /// This is only present if read through `PreImages`, and is not part of the oplog entry.
+        pre_image: Option<Document>,
     },
     /// A command such as the creation or deletion of a collection.
     Command {
         /// A unique identifier for this operation.
-        id: i64,
+        ///
+        /// This is only present on oplog entries written by MongoDB versions prior to 4.2.
+        id: Option<i64>,
+        /// The position of the operation in the oplog.
+        optime: OpTime,
         /// The time of the operation.
-        timestamp: DateTime<UTC>,
+        timestamp: DateTime<Utc>,
+        /// The election term of the primary that wrote the operation (`t`), if any.
+        ///
+        /// This is only present on oplog entries written by replica sets using protocol version 1.
+        term: Option<i64>,
+        /// The wall clock time of the primary when it wrote the operation (`wall`), if any.
+        ///
+        /// This is only present on oplog entries written by MongoDB 4.0 and later.
+        wall: Option<DateTime<Utc>>,
+        /// The version of the oplog entry format (`v`), if any.
+        version: Option<i64>,
+        /// The session and transaction the operation was written in, if any.
+        transaction: Option<Transaction>,
         /// The full namespace of the operation including its database and collection.
         namespace: String,
+        /// The UUID of the collection (`ui`), if any.
+        ///
+        /// This is only present on oplog entries written by MongoDB 3.6 and later and, unlike the
+        /// namespace, is unchanged when a collection is renamed.
+        uuid: Option<Uuid>,
         /// The BSON command.
         command: Document,
     },
     /// A command to apply multiple oplog operations at once.
     ApplyOps {
         /// A unique identifier for this operation.
-        id: i64,
+        ///
+        /// This is only present on oplog entries written by MongoDB versions prior to 4.2.
+        id: Option<i64>,
+        /// The position of the operation in the oplog.
+        optime: OpTime,
         /// The time of the operation.
-        timestamp: DateTime<UTC>,
+        timestamp: DateTime<Utc>,
+        /// The election term of the primary that wrote the operation (`t`), if any.
+        ///
+        /// This is only present on oplog entries written by replica sets using protocol version 1.
+        term: Option<i64>,
+        /// The wall clock time of the primary when it wrote the operation (`wall`), if any.
+        ///
+        /// This is only present on oplog entries written by MongoDB 4.0 and later.
+        wall: Option<DateTime<Utc>>,
+        /// The version of the oplog entry format (`v`), if any.
+        version: Option<i64>,
+        /// The session and transaction the operation was written in, if any.
+        transaction: Option<Transaction>,
         /// The full namespace of the operation including its database and collection.
         namespace: String,
+        /// The UUID of the collection (`ui`), if any.
+        ///
+        /// This is only present on oplog entries written by MongoDB 3.6 and later and, unlike the
+        /// namespace, is unchanged when a collection is renamed.
+        uuid: Option<Uuid>,
         /// A vector of operations to apply.
         operations: Vec<Operation>,
     },
 }
 
+/// The kind of an `Operation`, without any of its data.
+///
+/// This is useful for matching on operations without destructuring them or for restricting the
+/// kinds of operation yielded by an `Oplog` with `OplogBuilder::kinds`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum OperationKind {
+    /// A no-op.
+    Noop,
+    /// An insert of a document.
+    Insert,
+    /// An update of a document.
+    Update,
+    /// The deletion of a document.
+    Delete,
+    /// A command other than `applyOps`.
+    Command,
+    /// A command to apply multiple operations at once.
+    ApplyOps,
+}
+
+impl OperationKind {
+    /// Returns the value of the `op` field in the oplog for this kind of operation.
+    pub fn op(&self) -> &'static str {
+        match *self {
+            OperationKind::Noop => "n",
+            OperationKind::Insert => "i",
+            OperationKind::Update => "u",
+            OperationKind::Delete => "d",
+            OperationKind::Command | OperationKind::ApplyOps => "c",
+        }
+    }
+}
+
 impl Operation {
     /// Try to create a new Operation from a BSON document.
     ///
@@ -122,72 +310,608 @@ impl Operation {
         }
     }
 
-    /// Returns an operation from any BSON value.
-    fn from_bson(bson: &Bson) -> Result<Operation> {
+    /// Returns the kind of the operation.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[macro_use]
+    /// # extern crate bson;
+    /// # extern crate oplog;
+    /// # use bson::Bson;
+    /// use oplog::{Operation, OperationKind};
+    ///
+    /// # fn main() {
+    /// let document = doc! {
+    ///     "ts" => (Bson::TimeStamp(1479561394 << 32)),
+    ///     "h" => (-1742072865587022793i64),
+    ///     "v" => 2,
+    ///     "op" => "i",
+    ///     "ns" => "foo.bar",
+    ///     "o" => {
+    ///         "foo" => "bar"
+    ///     }
+    /// };
+    /// let operation = Operation::new(&document).unwrap();
+    ///
+    /// assert_eq!(operation.kind(), OperationKind::Insert);
+    /// # }
+    /// ```
+    pub fn kind(&self) -> OperationKind {
+        match *self {
+            Operation::Noop { .. } => OperationKind::Noop,
+            Operation::Insert { .. } => OperationKind::Insert,
+            Operation::Update { .. } => OperationKind::Update,
+            Operation::Delete { .. } => OperationKind::Delete,
+            Operation::Command { .. } => OperationKind::Command,
+            Operation::ApplyOps { .. } => OperationKind::ApplyOps,
+        }
+    }
+
+    /// Returns the position of the operation in the oplog.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[macro_use]
+    /// # extern crate bson;
+    /// # extern crate oplog;
+    /// # use bson::Bson;
+    /// use oplog::{OpTime, Operation};
+    ///
+    /// # fn main() {
+    /// let document = doc! {
+    ///     "ts" => (Bson::TimeStamp((1479561394 << 32) + 2)),
+    ///     "h" => (-1742072865587022793i64),
+    ///     "v" => 2,
+    ///     "op" => "i",
+    ///     "ns" => "foo.bar",
+    ///     "o" => {
+    ///         "foo" => "bar"
+    ///     }
+    /// };
+    /// let operation = Operation::new(&document).unwrap();
+    ///
+    /// assert_eq!(operation.optime(), OpTime::new(1479561394, 2));
+    /// # }
+    /// ```
+    pub fn optime(&self) -> OpTime {
+        match *self {
+            Operation::Noop { optime, .. } |
+            Operation::Insert { optime, .. } |
+            Operation::Update { optime, .. } |
+            Operation::Delete { optime, .. } |
+            Operation::Command { optime, .. } |
+            Operation::ApplyOps { optime, .. } => optime,
+        }
+    }
+
+    /// Returns the election term of the primary that wrote the operation, if any.
+    ///
+    /// A change in term between operations means that a new primary was elected, after which
+    /// operations read from the previous primary may have been rolled back.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[macro_use]
+    /// # extern crate bson;
+    /// # extern crate oplog;
+    /// # use bson::Bson;
+    /// use oplog::Operation;
+    ///
+    /// # fn main() {
+    /// let document = doc! {
+    ///     "ts" => (Bson::TimeStamp(1567578725 << 32)),
+    ///     "t" => 3i64,
+    ///     "v" => 2,
+    ///     "op" => "i",
+    ///     "ns" => "foo.bar",
+    ///     "o" => {
+    ///         "foo" => "bar"
+    ///     }
+    /// };
+    /// let operation = Operation::new(&document).unwrap();
+    ///
+    /// assert_eq!(operation.term(), Some(3));
+    /// # }
+    /// ```
+    pub fn term(&self) -> Option<i64> {
+        match *self {
+            Operation::Noop { term, .. } |
+            Operation::Insert { term, .. } |
+            Operation::Update { term, .. } |
+            Operation::Delete { term, .. } |
+            Operation::Command { term, .. } |
+            Operation::ApplyOps { term, .. } => term,
+        }
+    }
+
+    /// Returns the wall clock time of the primary when it wrote the operation, if any.
+    ///
+    /// Unlike the operation's `timestamp`, this has millisecond precision so is better suited to
+    /// measuring replication lag.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[macro_use]
+    /// # extern crate bson;
+    /// # extern crate chrono;
+    /// # extern crate oplog;
+    /// # use bson::Bson;
+    /// use chrono::{TimeZone, Utc};
+    /// use oplog::Operation;
+    ///
+    /// # fn main() {
+    /// let wall = Utc.timestamp_millis_opt(1567578725123).unwrap();
+    /// let document = doc! {
+    ///     "ts" => (Bson::TimeStamp(1567578725 << 32)),
+    ///     "t" => 3i64,
+    ///     "v" => 2,
+    ///     "op" => "i",
+    ///     "ns" => "foo.bar",
+    ///     "o" => {
+    ///         "foo" => "bar"
+    ///     },
+    ///     "wall" => (Bson::UtcDatetime(wall))
+    /// };
+    /// let operation = Operation::new(&document).unwrap();
+    ///
+    /// assert_eq!(operation.wall(), Some(wall));
+    /// # }
+    /// ```
+    pub fn wall(&self) -> Option<DateTime<Utc>> {
+        match *self {
+            Operation::Noop { wall, .. } |
+            Operation::Insert { wall, .. } |
+            Operation::Update { wall, .. } |
+            Operation::Delete { wall, .. } |
+            Operation::Command { wall, .. } |
+            Operation::ApplyOps { wall, .. } => wall,
+        }
+    }
+
+    /// Returns the version of the oplog entry format the operation was written in, if any.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[macro_use]
+    /// # extern crate bson;
+    /// # extern crate oplog;
+    /// # use bson::Bson;
+    /// use oplog::Operation;
+    ///
+    /// # fn main() {
+    /// let document = doc! {
+    ///     "ts" => (Bson::TimeStamp(1567578725 << 32)),
+    ///     "v" => 2,
+    ///     "op" => "i",
+    ///     "ns" => "foo.bar",
+    ///     "o" => {
+    ///         "foo" => "bar"
+    ///     }
+    /// };
+    /// let operation = Operation::new(&document).unwrap();
+    ///
+    /// assert_eq!(operation.version(), Some(2));
+    /// # }
+    /// ```
+    pub fn version(&self) -> Option<i64> {
+        match *self {
+            Operation::Noop { version, .. } |
+            Operation::Insert { version, .. } |
+            Operation::Update { version, .. } |
+            Operation::Delete { version, .. } |
+            Operation::Command { version, .. } |
+            Operation::ApplyOps { version, .. } => version,
+        }
+    }
+
+    /// Returns the session and transaction the operation was written in, if any.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[macro_use]
+    /// # extern crate bson;
+    /// # extern crate oplog;
+    /// # use bson::Bson;
+    /// use oplog::{Operation, TransactionState};
+    ///
+    /// # fn main() {
+    /// let document = doc! {
+    ///     "ts" => (Bson::TimeStamp(1567578725 << 32)),
+    ///     "t" => 1i64,
+    ///     "v" => 2,
+    ///     "op" => "c",
+    ///     "ns" => "admin.$cmd",
+    ///     "o" => {
+    ///         "applyOps" => [
+    ///             {
+    ///                 "op" => "i",
+    ///                 "ns" => "foo.bar",
+    ///                 "o" => {
+    ///                     "_id" => 1
+    ///                 }
+    ///             }
+    ///         ]
+    ///     },
+    ///     "lsid" => {
+    ///         "id" => 1
+    ///     },
+    ///     "txnNumber" => 1i64
+    /// };
+    /// let operation = Operation::new(&document).unwrap();
+    /// let transaction = operation.transaction().unwrap();
+    ///
+    /// assert_eq!(transaction.state, Some(TransactionState::Committed));
+    /// # }
+    /// ```
+    pub fn transaction(&self) -> Option<&Transaction> {
+        match *self {
+            Operation::Noop { ref transaction, .. } |
+            Operation::Insert { ref transaction, .. } |
+            Operation::Update { ref transaction, .. } |
+            Operation::Delete { ref transaction, .. } |
+            Operation::Command { ref transaction, .. } |
+            Operation::ApplyOps { ref transaction, .. } => transaction.as_ref(),
+        }
+    }
+
+    /// Returns the kind of command if the operation is a command.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[macro_use]
+    /// # extern crate bson;
+    /// # extern crate oplog;
+    /// # use bson::Bson;
+    /// use oplog::{CommandKind, Operation};
+    ///
+    /// # fn main() {
+    /// let document = doc! {
+    ///     "ts" => (Bson::TimeStamp(1479561394 << 32)),
+    ///     "h" => (-1742072865587022793i64),
+    ///     "v" => 2,
+    ///     "op" => "c",
+    ///     "ns" => "foo.$cmd",
+    ///     "o" => {
+    ///         "drop" => "bar"
+    ///     }
+    /// };
+    /// let operation = Operation::new(&document).unwrap();
+    ///
+    /// assert_eq!(operation.command_kind(),
+    ///            Some(CommandKind::Drop { namespace: "foo.bar".into() }));
+    /// # }
+    /// ```
+    pub fn command_kind(&self) -> Option<CommandKind> {
+        match *self {
+            Operation::Command { ref namespace, ref command, .. } => {
+                let database = namespace.split('.').next().unwrap_or(namespace);
+
+                Some(CommandKind::new(database, command))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the given previous version of a document with this update applied.
+    ///
+    /// Returns an error if the operation is not an update, uses an unsupported modifier or does
+    /// not apply to the document (e.g. incrementing a string). See `UpdateDescription` for the
+    /// changes made by an update regardless of the document.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[macro_use]
+    /// # extern crate bson;
+    /// # extern crate oplog;
+    /// # use bson::Bson;
+    /// use oplog::Operation;
+    ///
+    /// # fn main() {
+    /// let document = doc! {
+    ///     "ts" => (Bson::TimeStamp(1479561394 << 32)),
+    ///     "h" => (-1742072865587022793i64),
+    ///     "v" => 2,
+    ///     "op" => "u",
+    ///     "ns" => "foo.bar",
+    ///     "o2" => {
+    ///         "_id" => 1
+    ///     },
+    ///     "o" => {
+    ///         "$inc" => { "visits" => 1 },
+    ///         "$push" => { "tags" => "new" }
+    ///     }
+    /// };
+    /// let operation = Operation::new(&document).unwrap();
+    /// let previous = doc! { "_id" => 1, "visits" => 1, "tags" => ["old"] };
+    ///
+    /// assert_eq!(operation.apply_update(&previous).unwrap(),
+    ///            doc! { "_id" => 1, "visits" => 2, "tags" => ["old", "new"] });
+    /// # }
+    /// ```
+    pub fn apply_update(&self, document: &Document) -> Result<Document> {
+        match *self {
+            Operation::Update { ref update, .. } => update::apply(document, update),
+            _ => Err(Error::InvalidOperation),
+        }
+    }
+
+    /// Returns the oplog entry for the operation.
+    ///
+    /// This is the inverse of `Operation::new` so the entry decodes into an equal operation,
+    /// though any fields not kept by the operation (e.g. as read from a change stream) are lost.
+    /// The part an `applyOps` command plays in a transaction is recorded with its `partialTxn`
+    /// and `prepare` fields, and operations sharing the timestamp of their `applyOps` command are
+    /// recorded without their own as in a transaction.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[macro_use]
+    /// # extern crate bson;
+    /// # extern crate oplog;
+    /// # use bson::Bson;
+    /// use oplog::Operation;
+    ///
+    /// # fn main() {
+    /// let document = doc! {
+    ///     "ts" => (Bson::TimeStamp(1479561394 << 32)),
+    ///     "h" => (-1742072865587022793i64),
+    ///     "v" => 2,
+    ///     "op" => "i",
+    ///     "ns" => "foo.bar",
+    ///     "o" => {
+    ///         "foo" => "bar"
+    ///     }
+    /// };
+    /// let operation = Operation::new(&document).unwrap();
+    ///
+    /// assert_eq!(operation.to_document(), document);
+    /// # }
+    /// ```
+    pub fn to_document(&self) -> Document {
+        let id = match *self {
+            Operation::Noop { id, .. } |
+            Operation::Insert { id, .. } |
+            Operation::Update { id, .. } |
+            Operation::Delete { id, .. } |
+            Operation::Command { id, .. } |
+            Operation::ApplyOps { id, .. } => id,
+        };
+
+        let mut entry = doc! { "ts" => (self.optime()) };
+
+        if let Some(term) = self.term() {
+            entry.insert("t", term);
+        }
+        if let Some(id) = id {
+            entry.insert("h", id);
+        }
+        if let Some(version) = self.version() {
+            entry.insert("v", integer_to_bson(version));
+        }
+
+        match *self {
+            Operation::Noop { ref message, ref object, .. } => {
+                let mut object = object.clone();
+                if object.get_str("msg").unwrap_or("") != message {
+                    object.insert("msg", message.clone());
+                }
+
+                entry.insert("op", "n");
+                entry.insert("ns", "");
+                entry.insert("o", object);
+            }
+            Operation::Insert { ref namespace, uuid, ref document, .. } => {
+                entry.insert("op", "i");
+                insert_namespace(&mut entry, namespace, uuid);
+                entry.insert("o", document.clone());
+            }
+            Operation::Update { ref namespace,
+                                uuid,
+                                ref query,
+                                ref update,
+                                pre_image_optime,
+                                pre_image_term,
+                                .. } => {
+                entry.insert("op", "u");
+                insert_namespace(&mut entry, namespace, uuid);
+                entry.insert("o", update.clone());
+                entry.insert("o2", query.clone());
+
+                if let Some(optime) = pre_image_optime {
+                    entry.insert("preImageOpTime", linked_optime_to_bson(optime, pre_image_term));
+                }
+            }
+            Operation::Delete { ref namespace,
+                                uuid,
+                                ref query,
+                                pre_image_optime,
+                                pre_image_term,
+                                .. } => {
+                entry.insert("op", "d");
+                insert_namespace(&mut entry, namespace, uuid);
+                entry.insert("o", query.clone());
+
+                if let Some(optime) = pre_image_optime {
+                    entry.insert("preImageOpTime", linked_optime_to_bson(optime, pre_image_term));
+                }
+            }
+            Operation::Command { ref namespace, uuid, ref command, .. } => {
+                entry.insert("op", "c");
+                insert_namespace(&mut entry, namespace, uuid);
+                entry.insert("o", command.clone());
+            }
+            Operation::ApplyOps { ref namespace,
+                                  uuid,
+                                  optime,
+                                  ref operations,
+                                  ref transaction,
+                                  .. } => {
+                let operations = operations.iter()
+                                           .map(|operation| {
+                                               let mut document = operation.to_document();
+                                               if operation.optime() == optime {
+                                                   document.remove("ts");
+                                               }
+
+                                               Bson::Document(document)
+                                           })
+                                           .collect::<Vec<_>>();
+                let mut command = doc! { "applyOps" => operations };
+
+                match transaction.as_ref().and_then(|transaction| transaction.state) {
+                    Some(TransactionState::Partial) => {
+                        command.insert("partialTxn", true);
+                    }
+                    Some(TransactionState::Prepared) => {
+                        command.insert("prepare", true);
+                    }
+                    _ => {}
+                }
+
+                entry.insert("op", "c");
+                insert_namespace(&mut entry, namespace, uuid);
+                entry.insert("o", command);
+            }
+        }
+
+        if let Some(wall) = self.wall() {
+            entry.insert("wall", Bson::UtcDatetime(wall));
+        }
+
+        if let Some(transaction) = self.transaction() {
+            entry.insert("lsid", transaction.session_id.clone());
+            entry.insert("txnNumber", transaction.txn_number);
+
+            if let Some(statement_id) = transaction.statement_id {
+                entry.insert("stmtId", statement_id);
+            }
+            if let Some(prev_optime) = transaction.prev_optime {
+                entry.insert("prevOpTime",
+                             linked_optime_to_bson(prev_optime, transaction.prev_term));
+            }
+        }
+
+        entry
+    }
+
+    /// Returns an operation from any BSON value in an `applyOps` command at the given position.
+    ///
+    /// Operations in a transaction do not have their own timestamp so take that of the command.
+    fn from_bson(bson: &Bson, ts: OpTime) -> Result<Operation> {
         match *bson {
-            Bson::Document(ref document) => Operation::new(document),
+            Bson::Document(ref document) if document.contains_key("ts") => {
+                Operation::new(document)
+            }
+            Bson::Document(ref document) => {
+                let mut document = document.clone();
+                document.insert("ts", ts);
+
+                Operation::new(&document)
+            }
             _ => Err(Error::InvalidOperation),
         }
     }
 
     /// Returns a no-op operation for a given document.
     fn from_noop(document: &Document) -> Result<Operation> {
-        let h = document.get_i64("h")?;
-        let ts = document.get_time_stamp("ts")?;
+        let h = optional(document.get_i64("h"))?;
+        let ts = OpTime::from(document.get_time_stamp("ts")?);
         let o = document.get_document("o")?;
-        let msg = o.get_str("msg")?;
+        let msg = optional(o.get_str("msg"))?.unwrap_or("");
 
         Ok(Operation::Noop {
             id: h,
-            timestamp: timestamp_to_datetime(ts),
+            optime: ts,
+            timestamp: ts.to_datetime(),
+            term: integer(document, "t")?,
+            wall: optional(document.get_utc_datetime("wall"))?.cloned(),
+            version: integer(document, "v")?,
+            transaction: Transaction::from_entry(document)?,
             message: msg.into(),
+            object: o.clone(),
         })
     }
 
     /// Return an insert operation for a given document.
     fn from_insert(document: &Document) -> Result<Operation> {
-        let h = document.get_i64("h")?;
-        let ts = document.get_time_stamp("ts")?;
+        let h = optional(document.get_i64("h"))?;
+        let ts = OpTime::from(document.get_time_stamp("ts")?);
         let ns = document.get_str("ns")?;
         let o = document.get_document("o")?;
 
         Ok(Operation::Insert {
             id: h,
-            timestamp: timestamp_to_datetime(ts),
+            optime: ts,
+            timestamp: ts.to_datetime(),
+            term: integer(document, "t")?,
+            wall: optional(document.get_utc_datetime("wall"))?.cloned(),
+            version: integer(document, "v")?,
+            transaction: Transaction::from_entry(document)?,
             namespace: ns.into(),
+            uuid: uuid(document, "ui")?,
             document: o.to_owned(),
         })
     }
 
     /// Return an update operation for a given document.
     fn from_update(document: &Document) -> Result<Operation> {
-        let h = document.get_i64("h")?;
-        let ts = document.get_time_stamp("ts")?;
+        let h = optional(document.get_i64("h"))?;
+        let ts = OpTime::from(document.get_time_stamp("ts")?);
         let ns = document.get_str("ns")?;
         let o = document.get_document("o")?;
         let o2 = document.get_document("o2")?;
+        let (pre_image_optime, pre_image_term) = linked_optime(document, "preImageOpTime")?;
 
         Ok(Operation::Update {
             id: h,
-            timestamp: timestamp_to_datetime(ts),
+            optime: ts,
+            timestamp: ts.to_datetime(),
+            term: integer(document, "t")?,
+            wall: optional(document.get_utc_datetime("wall"))?.cloned(),
+            version: integer(document, "v")?,
+            transaction: Transaction::from_entry(document)?,
             namespace: ns.into(),
+            uuid: uuid(document, "ui")?,
             query: o2.to_owned(),
             update: o.to_owned(),
+            full_document: None,
+            pre_image_optime,
+            pre_image_term,
+            pre_image: None,
         })
     }
 
     /// Return a delete operation for a given document.
     fn from_delete(document: &Document) -> Result<Operation> {
-        let h = document.get_i64("h")?;
-        let ts = document.get_time_stamp("ts")?;
+        let h = optional(document.get_i64("h"))?;
+        let ts = OpTime::from(document.get_time_stamp("ts")?);
         let ns = document.get_str("ns")?;
         let o = document.get_document("o")?;
+        let (pre_image_optime, pre_image_term) = linked_optime(document, "preImageOpTime")?;
 
         Ok(Operation::Delete {
             id: h,
-            timestamp: timestamp_to_datetime(ts),
+            optime: ts,
+            timestamp: ts.to_datetime(),
+            term: integer(document, "t")?,
+            wall: optional(document.get_utc_datetime("wall"))?.cloned(),
+            version: integer(document, "v")?,
+            transaction: Transaction::from_entry(document)?,
             namespace: ns.into(),
+            uuid: uuid(document, "ui")?,
             query: o.to_owned(),
+            pre_image_optime,
+            pre_image_term,
+            pre_image: None,
         })
     }
 
@@ -196,29 +920,41 @@ impl Operation {
     /// Note that this can return either an `Operation::Command` or an `Operation::ApplyOps` when
     /// successful.
     fn from_command(document: &Document) -> Result<Operation> {
-        let h = document.get_i64("h")?;
-        let ts = document.get_time_stamp("ts")?;
+        let h = optional(document.get_i64("h"))?;
+        let ts = OpTime::from(document.get_time_stamp("ts")?);
         let ns = document.get_str("ns")?;
         let o = document.get_document("o")?;
 
         match o.get_array("applyOps") {
             Ok(ops) => {
                 let operations = ops.iter()
-                                    .map(|bson| Operation::from_bson(bson))
+                                    .map(|op| Operation::from_bson(op, ts))
                                     .collect::<Result<Vec<Operation>>>()?;
 
                 Ok(Operation::ApplyOps {
                     id: h,
-                    timestamp: timestamp_to_datetime(ts),
+                    optime: ts,
+                    timestamp: ts.to_datetime(),
+                    term: integer(document, "t")?,
+                    wall: optional(document.get_utc_datetime("wall"))?.cloned(),
+                    version: integer(document, "v")?,
+                    transaction: Transaction::from_entry(document)?,
                     namespace: ns.into(),
-                    operations: operations,
+                    uuid: uuid(document, "ui")?,
+                    operations,
                 })
             }
             Err(_) => {
                 Ok(Operation::Command {
                     id: h,
-                    timestamp: timestamp_to_datetime(ts),
+                    optime: ts,
+                    timestamp: ts.to_datetime(),
+                    term: integer(document, "t")?,
+                    wall: optional(document.get_utc_datetime("wall"))?.cloned(),
+                    version: integer(document, "v")?,
+                    transaction: Transaction::from_entry(document)?,
                     namespace: ns.into(),
+                    uuid: uuid(document, "ui")?,
                     command: o.to_owned(),
                 })
             }
@@ -226,49 +962,55 @@ impl Operation {
     }
 }
 
+impl From<Operation> for Document {
+    fn from(operation: Operation) -> Document {
+        operation.to_document()
+    }
+}
+
 impl fmt::Display for Operation {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            Operation::Noop { id, timestamp, ref message } => {
-                write!(f, "No-op #{} at {}: {}", id, timestamp, message)
+            Operation::Noop { id, timestamp, ref message, .. } => {
+                write!(f, "No-op{} at {}: {}", Id(id), timestamp, message)
             }
-            Operation::Insert { id, timestamp, ref namespace, ref document } => {
+            Operation::Insert { id, timestamp, ref namespace, ref document, .. } => {
                 write!(f,
-                       "Insert #{} into {} at {}: {}",
-                       id,
+                       "Insert{} into {} at {}: {}",
+                       Id(id),
                        namespace,
                        timestamp,
                        document)
             }
-            Operation::Update { id, timestamp, ref namespace, ref query, ref update } => {
+            Operation::Update { id, timestamp, ref namespace, ref query, ref update, .. } => {
                 write!(f,
-                       "Update #{} {} with {} at {}: {}",
-                       id,
+                       "Update{} {} with {} at {}: {}",
+                       Id(id),
                        namespace,
                        query,
                        timestamp,
                        update)
             }
-            Operation::Delete { id, timestamp, ref namespace, ref query } => {
+            Operation::Delete { id, timestamp, ref namespace, ref query, .. } => {
                 write!(f,
-                       "Delete #{} from {} at {}: {}",
-                       id,
+                       "Delete{} from {} at {}: {}",
+                       Id(id),
                        namespace,
                        timestamp,
                        query)
             }
-            Operation::Command { id, timestamp, ref namespace, ref command } => {
+            Operation::Command { id, timestamp, ref namespace, ref command, .. } => {
                 write!(f,
-                       "Command #{} {} at {}: {}",
-                       id,
+                       "Command{} {} at {}: {}",
+                       Id(id),
                        namespace,
                        timestamp,
                        command)
             }
-            Operation::ApplyOps { id, timestamp, ref namespace, ref operations } => {
+            Operation::ApplyOps { id, timestamp, ref namespace, ref operations, .. } => {
                 write!(f,
-                       "ApplyOps #{} {} at {}: {} operations",
-                       id,
+                       "ApplyOps{} {} at {}: {} operations",
+                       Id(id),
                        namespace,
                        timestamp,
                        operations.len())
@@ -277,21 +1019,338 @@ impl fmt::Display for Operation {
     }
 }
 
-/// Convert a BSON timestamp into a UTC `DateTime`.
-fn timestamp_to_datetime(timestamp: i64) -> DateTime<UTC> {
-    let seconds = timestamp >> 32;
-    let nanoseconds = ((timestamp & 0xFFFFFFFF) * 1000000) as u32;
+/// Displays an optional operation identifier, if present.
+struct Id(Option<i64>);
+
+impl fmt::Display for Id {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(id) => write!(f, " #{}", id),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Inserts the namespace and any collection UUID of an operation into an oplog entry.
+fn insert_namespace(entry: &mut Document, namespace: &str, uuid: Option<Uuid>) {
+    entry.insert("ns", namespace);
 
-    UTC.timestamp(seconds, nanoseconds)
+    if let Some(uuid) = uuid {
+        entry.insert("ui", Bson::Binary(BinarySubtype::Uuid, uuid.as_bytes().to_vec()));
+    }
+}
+
+/// Returns the collection UUID in the given field of a document, if any.
+pub(crate) fn uuid(document: &Document, key: &str) -> Result<Option<Uuid>> {
+    match document.get(key) {
+        Some(&Bson::Binary(BinarySubtype::Uuid, ref bytes)) => {
+            match Uuid::from_slice(bytes) {
+                Ok(uuid) => Ok(Some(uuid)),
+                Err(_) => Err(ValueAccessError::UnexpectedType.into()),
+            }
+        }
+        Some(_) => Err(ValueAccessError::UnexpectedType.into()),
+        None => Ok(None),
+    }
+}
+
+/// Returns the value of an optional integer field of either 32 or 64 bits.
+pub(crate) fn integer(document: &Document, key: &str) -> Result<Option<i64>> {
+    match document.get(key) {
+        Some(&Bson::I32(value)) => Ok(Some(i64::from(value))),
+        Some(&Bson::I64(value)) => Ok(Some(value)),
+        Some(_) => Err(ValueAccessError::UnexpectedType.into()),
+        None => Ok(None),
+    }
+}
+
+/// Returns the position and term of an optional field linking to another oplog entry (e.g.
+/// `prevOpTime`).
+pub(crate) fn linked_optime(document: &Document,
+                            key: &str)
+                            -> Result<(Option<OpTime>, Option<i64>)> {
+    match optional(document.get_document(key))? {
+        Some(linked) => {
+            Ok((Some(OpTime::from(linked.get_time_stamp("ts")?)), integer(linked, "t")?))
+        }
+        None => Ok((None, None)),
+    }
+}
+
+/// Returns the field linking to another oplog entry with the given position and term.
+fn linked_optime_to_bson(optime: OpTime, term: Option<i64>) -> Document {
+    let mut linked = doc! { "ts" => optime };
+    if let Some(term) = term {
+        linked.insert("t", term);
+    }
+
+    linked
+}
+
+/// Returns the given integer as a 32-bit BSON integer if it fits, otherwise a 64-bit one.
+fn integer_to_bson(value: i64) -> Bson {
+    if i64::from(value as i32) == value {
+        Bson::I32(value as i32)
+    } else {
+        Bson::I64(value)
+    }
+}
+
+/// Returns the value of an optional field, treating a missing field as `None` but any other
+/// error (e.g. an unexpected type) as an error.
+pub(crate) fn optional<T>(result: ValueAccessResult<T>) -> Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(ValueAccessError::NotPresent) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use Error;
-    use bson::{Bson, ValueAccessError};
-    use chrono::{UTC, TimeZone};
+    use {Error, OpTime, Transaction, TransactionState};
+    use bson::{Bson, Document, ValueAccessError};
+    use bson::spec::BinarySubtype;
+    use chrono::{TimeZone, Utc};
+    use quickcheck::{quickcheck, Arbitrary, Gen};
+    use uuid::Uuid;
     use super::Operation;
 
+    impl Arbitrary for Operation {
+        fn arbitrary<G: Gen>(g: &mut G) -> Operation {
+            match u8::arbitrary(g) % 6 {
+                5 => {
+                    let state = match u8::arbitrary(g) % 4 {
+                        0 => Some(TransactionState::Partial),
+                        1 => Some(TransactionState::Prepared),
+                        2 => Some(TransactionState::Committed),
+                        _ => None,
+                    };
+                    let optime = optime(g);
+                    let count = u8::arbitrary(g) % 4;
+                    let operations = (0..count)
+                        .map(|_| {
+                            // Operations in a transaction share the timestamp of their command.
+                            let inner = if bool::arbitrary(g) { optime } else { self::optime(g) };
+
+                            operation(g, inner, None)
+                        })
+                        .collect();
+
+                    Operation::ApplyOps {
+                        id: Arbitrary::arbitrary(g),
+                        optime,
+                        timestamp: optime.to_datetime(),
+                        term: Arbitrary::arbitrary(g),
+                        wall: wall(g),
+                        version: Arbitrary::arbitrary(g),
+                        transaction: state.map(|state| transaction(g, Some(state))),
+                        namespace: "admin.$cmd".into(),
+                        uuid: uuid(g),
+                        operations,
+                    }
+                }
+                _ => {
+                    let transaction = if bool::arbitrary(g) {
+                        Some(transaction(g, None))
+                    } else {
+                        None
+                    };
+
+                    let optime = optime(g);
+
+                    operation(g, optime, transaction)
+                }
+            }
+        }
+    }
+
+    fn operation<G: Gen>(g: &mut G,
+                         optime: OpTime,
+                         transaction: Option<Transaction>)
+                         -> Operation {
+        let id = Arbitrary::arbitrary(g);
+        let term = Arbitrary::arbitrary(g);
+        let wall = wall(g);
+        let version = Arbitrary::arbitrary(g);
+        let (pre_image_optime, pre_image_term) = if bool::arbitrary(g) {
+            (Some(self::optime(g)), Arbitrary::arbitrary(g))
+        } else {
+            (None, None)
+        };
+
+        match u8::arbitrary(g) % 5 {
+            0 => {
+                let message = String::arbitrary(g);
+                let mut object = if message.is_empty() && bool::arbitrary(g) {
+                    Document::new()
+                } else {
+                    doc! { "msg" => (message.clone()) }
+                };
+                if let Some(value) = Option::<i32>::arbitrary(g) {
+                    object.insert("preImageOpTime", value);
+                }
+
+                Operation::Noop {
+                    id,
+                    optime,
+                    timestamp: optime.to_datetime(),
+                    term,
+                    wall,
+                    version,
+                    transaction,
+                    message,
+                    object,
+                }
+            }
+            1 => {
+                Operation::Insert {
+                    id,
+                    optime,
+                    timestamp: optime.to_datetime(),
+                    term,
+                    wall,
+                    version,
+                    transaction,
+                    namespace: Arbitrary::arbitrary(g),
+                    uuid: uuid(g),
+                    document: document(g),
+                }
+            }
+            2 => {
+                Operation::Update {
+                    id,
+                    optime,
+                    timestamp: optime.to_datetime(),
+                    term,
+                    wall,
+                    version,
+                    transaction,
+                    namespace: Arbitrary::arbitrary(g),
+                    uuid: uuid(g),
+                    query: document(g),
+                    update: doc! { "$set" => (document(g)) },
+                    full_document: None,
+                    pre_image_optime,
+                    pre_image_term,
+                    pre_image: None,
+                }
+            }
+            3 => {
+                Operation::Delete {
+                    id,
+                    optime,
+                    timestamp: optime.to_datetime(),
+                    term,
+                    wall,
+                    version,
+                    transaction,
+                    namespace: Arbitrary::arbitrary(g),
+                    uuid: uuid(g),
+                    query: document(g),
+                    pre_image_optime,
+                    pre_image_term,
+                    pre_image: None,
+                }
+            }
+            _ => {
+                Operation::Command {
+                    id,
+                    optime,
+                    timestamp: optime.to_datetime(),
+                    term,
+                    wall,
+                    version,
+                    transaction,
+                    namespace: "foo.$cmd".into(),
+                    uuid: uuid(g),
+                    command: doc! { "create" => (String::arbitrary(g)) },
+                }
+            }
+        }
+    }
+
+    fn transaction<G: Gen>(g: &mut G, state: Option<TransactionState>) -> Transaction {
+        let (prev_optime, prev_term) = match u8::arbitrary(g) % 3 {
+            0 => (None, None),
+            1 => (Some(OpTime::new(0, 0)), Some(-1)),
+            _ => (Some(optime(g)), Arbitrary::arbitrary(g)),
+        };
+
+        Transaction {
+            session_id: doc! { "id" => (i64::arbitrary(g)) },
+            txn_number: Arbitrary::arbitrary(g),
+            statement_id: Arbitrary::arbitrary(g),
+            prev_optime,
+            prev_term,
+            state,
+        }
+    }
+
+    fn optime<G: Gen>(g: &mut G) -> OpTime {
+        OpTime::new(Arbitrary::arbitrary(g), Arbitrary::arbitrary(g))
+    }
+
+    fn wall<G: Gen>(g: &mut G) -> Option<::chrono::DateTime<Utc>> {
+        Option::<u32>::arbitrary(g)
+            .map(|seconds| Utc.timestamp_millis_opt(i64::from(seconds) * 1000 + 123).unwrap())
+    }
+
+    fn uuid<G: Gen>(g: &mut G) -> Option<Uuid> {
+        Option::<u64>::arbitrary(g).map(|bits| {
+            let mut bytes = [0; 16];
+            bytes[..8].copy_from_slice(&bits.to_be_bytes());
+
+            Uuid::from_bytes(bytes)
+        })
+    }
+
+    fn document<G: Gen>(g: &mut G) -> Document {
+        doc! { "_id" => (i32::arbitrary(g)), "foo" => (String::arbitrary(g)) }
+    }
+
+    #[test]
+    fn operation_round_trips_through_documents() {
+        fn round_trips(operation: Operation) -> bool {
+            Operation::new(&operation.to_document()).ok() == Some(operation)
+        }
+
+        quickcheck(round_trips as fn(Operation) -> bool);
+    }
+
+    #[test]
+    fn operation_converts_transactions_back_into_documents() {
+        let doc = doc! {
+            "ts" => (Bson::TimeStamp((1567578725 << 32) + 3)),
+            "v" => 2,
+            "op" => "c",
+            "ns" => "admin.$cmd",
+            "o" => {
+                "applyOps" => [
+                    {
+                        "op" => "i",
+                        "ns" => "foo.bar",
+                        "o" => {
+                            "_id" => 1
+                        }
+                    }
+                ],
+                "partialTxn" => true
+            },
+            "lsid" => {
+                "id" => 1
+            },
+            "txnNumber" => 2i64,
+            "stmtId" => 0,
+            "prevOpTime" => {
+                "ts" => (Bson::TimeStamp((1567578725 << 32) + 1))
+            }
+        };
+        let operation = Operation::new(&doc).unwrap();
+
+        assert_eq!(operation.to_document(), doc);
+    }
+
     #[test]
     fn operation_converts_noops() {
         let doc = doc! {
@@ -308,9 +1367,15 @@ mod tests {
 
         assert_eq!(operation,
                    Operation::Noop {
-                       id: -2135725856567446411i64,
-                       timestamp: UTC.timestamp(1479419535, 0),
+                       id: Some(-2135725856567446411i64),
+                       optime: OpTime::new(1479419535, 0),
+                       timestamp: Utc.timestamp_opt(1479419535, 0).unwrap(),
+                       term: None,
+                       wall: None,
+                       version: Some(2),
+                       transaction: None,
                        message: "initiating set".into(),
+                       object: doc! { "msg" => "initiating set" },
                    });
     }
 
@@ -330,9 +1395,15 @@ mod tests {
 
         assert_eq!(operation,
                    Operation::Insert {
-                       id: -1742072865587022793i64,
-                       timestamp: UTC.timestamp(1479561394, 0),
+                       id: Some(-1742072865587022793i64),
+                       optime: OpTime::new(1479561394, 0),
+                       timestamp: Utc.timestamp_opt(1479561394, 0).unwrap(),
+                       term: None,
+                       wall: None,
+                       version: Some(2),
+                       transaction: None,
                        namespace: "foo.bar".into(),
+                       uuid: None,
                        document: doc! { "foo" => "bar" },
                    });
     }
@@ -358,11 +1429,21 @@ mod tests {
 
         assert_eq!(operation,
                    Operation::Update {
-                       id: 3511341713062188019i64,
-                       timestamp: UTC.timestamp(1479561033, 0),
+                       id: Some(3511341713062188019i64),
+                       optime: OpTime::new(1479561033, 0),
+                       timestamp: Utc.timestamp_opt(1479561033, 0).unwrap(),
+                       term: None,
+                       wall: None,
+                       version: Some(2),
+                       transaction: None,
                        namespace: "foo.bar".into(),
+                       uuid: None,
                        query: doc! { "_id" => 1 },
                        update: doc! { "$set" => { "foo" => "baz" } },
+                       full_document: None,
+                       pre_image_optime: None,
+                       pre_image_term: None,
+                       pre_image: None,
                    });
     }
 
@@ -382,10 +1463,19 @@ mod tests {
 
         assert_eq!(operation,
                    Operation::Delete {
-                       id: -5457382347563537847i64,
-                       timestamp: UTC.timestamp(1479421186, 0),
+                       id: Some(-5457382347563537847i64),
+                       optime: OpTime::new(1479421186, 0),
+                       timestamp: Utc.timestamp_opt(1479421186, 0).unwrap(),
+                       term: None,
+                       wall: None,
+                       version: Some(2),
+                       transaction: None,
                        namespace: "foo.bar".into(),
+                       uuid: None,
                        query: doc! { "_id" => 1 },
+                       pre_image_optime: None,
+                       pre_image_term: None,
+                       pre_image: None,
                    });
     }
 
@@ -405,13 +1495,131 @@ mod tests {
 
         assert_eq!(operation,
                    Operation::Command {
-                       id: -7222343681970774929i64,
-                       timestamp: UTC.timestamp(1479553955, 0),
+                       id: Some(-7222343681970774929i64),
+                       optime: OpTime::new(1479553955, 0),
+                       timestamp: Utc.timestamp_opt(1479553955, 0).unwrap(),
+                       term: None,
+                       wall: None,
+                       version: Some(2),
+                       transaction: None,
                        namespace: "test.$cmd".into(),
+                       uuid: None,
                        command: doc! { "create" => "foo" },
                    });
     }
 
+    #[test]
+    fn operation_keeps_increment_out_of_timestamp() {
+        let doc = doc! {
+            "ts" => (Bson::TimeStamp((1479561394 << 32) + 1500)),
+            "h" => (-1742072865587022793i64),
+            "v" => 2,
+            "op" => "i",
+            "ns" => "foo.bar",
+            "o" => {
+                "foo" => "bar"
+            }
+        };
+        let operation = Operation::new(&doc).unwrap();
+
+        assert_eq!(operation,
+                   Operation::Insert {
+                       id: Some(-1742072865587022793i64),
+                       optime: OpTime::new(1479561394, 1500),
+                       timestamp: Utc.timestamp_opt(1479561394, 0).unwrap(),
+                       term: None,
+                       wall: None,
+                       version: Some(2),
+                       transaction: None,
+                       namespace: "foo.bar".into(),
+                       uuid: None,
+                       document: doc! { "foo" => "bar" },
+                   });
+    }
+
+    #[test]
+    fn operation_converts_operations_without_ids() {
+        let doc = doc! {
+            "ts" => (Bson::TimeStamp((1567578725 << 32) + 1)),
+            "t" => 1i64,
+            "v" => 2,
+            "op" => "i",
+            "ns" => "foo.bar",
+            "o" => {
+                "foo" => "bar"
+            },
+            "wall" => (Bson::UtcDatetime(Utc.timestamp_opt(1567578725, 0).unwrap()))
+        };
+        let operation = Operation::new(&doc).unwrap();
+
+        assert_eq!(operation,
+                   Operation::Insert {
+                       id: None,
+                       optime: OpTime::new(1567578725, 1),
+                       timestamp: Utc.timestamp_opt(1567578725, 0).unwrap(),
+                       term: Some(1),
+                       wall: Some(Utc.timestamp_opt(1567578725, 0).unwrap()),
+                       version: Some(2),
+                       transaction: None,
+                       namespace: "foo.bar".into(),
+                       uuid: None,
+                       document: doc! { "foo" => "bar" },
+                   });
+    }
+
+    #[test]
+    fn operation_converts_collection_uuids() {
+        let doc = doc! {
+            "ts" => (Bson::TimeStamp(1567578725 << 32)),
+            "t" => 1i64,
+            "v" => 2,
+            "op" => "d",
+            "ns" => "foo.bar",
+            "ui" => (Bson::Binary(BinarySubtype::Uuid, vec![1; 16])),
+            "o" => {
+                "_id" => 1
+            }
+        };
+        let operation = Operation::new(&doc).unwrap();
+
+        assert_eq!(operation,
+                   Operation::Delete {
+                       id: None,
+                       optime: OpTime::new(1567578725, 0),
+                       timestamp: Utc.timestamp_opt(1567578725, 0).unwrap(),
+                       term: Some(1),
+                       wall: None,
+                       version: Some(2),
+                       transaction: None,
+                       namespace: "foo.bar".into(),
+                       uuid: Some(Uuid::from_bytes([1; 16])),
+                       query: doc! { "_id" => 1 },
+                       pre_image_optime: None,
+                       pre_image_term: None,
+                       pre_image: None,
+                   });
+    }
+
+    #[test]
+    fn operation_returns_invalid_ids() {
+        let doc = doc! {
+            "ts" => (Bson::TimeStamp(1479561394 << 32)),
+            "h" => "foo",
+            "v" => 2,
+            "op" => "i",
+            "ns" => "foo.bar",
+            "o" => {
+                "foo" => "bar"
+            }
+        };
+        let operation = Operation::new(&doc);
+
+        match operation {
+            Err(Error::MissingField(err)) => assert_eq!(err, ValueAccessError::UnexpectedType),
+            _ => panic!("Expected unexpected type."),
+        }
+    }
+
     #[test]
     fn operation_returns_unknown_operations() {
         let doc = doc! { "op" => "x" };
@@ -462,15 +1670,91 @@ mod tests {
 
         assert_eq!(operation,
                    Operation::ApplyOps {
-                       id: -3262249347345468996i64,
-                       timestamp: UTC.timestamp(1483789052, 0),
+                       id: Some(-3262249347345468996i64),
+                       optime: OpTime::new(1483789052, 0),
+                       timestamp: Utc.timestamp_opt(1483789052, 0).unwrap(),
+                       term: None,
+                       wall: None,
+                       version: Some(2),
+                       transaction: None,
                        namespace: "foo.$cmd".into(),
+                       uuid: None,
                        operations: vec![Operation::Insert {
-                                            id: -1742072865587022793i64,
-                                            timestamp: UTC.timestamp(1479561394, 0),
+                                            id: Some(-1742072865587022793i64),
+                                            optime: OpTime::new(1479561394, 0),
+                                            timestamp: Utc.timestamp_opt(1479561394, 0).unwrap(),
+                                            term: Some(2),
+                                            wall: None,
+                                            version: None,
+                                            transaction: None,
                                             namespace: "foo.bar".into(),
+                                            uuid: None,
                                             document: doc! { "_id" => 1, "foo" => "bar" },
                                         }],
                    });
     }
+
+    #[test]
+    fn operation_returns_transactions() {
+        let doc = doc! {
+            "ts" => (Bson::TimeStamp((1567578725 << 32) + 3)),
+            "t" => 1i64,
+            "v" => 2,
+            "op" => "c",
+            "ns" => "admin.$cmd",
+            "o" => {
+                "applyOps" => [
+                    {
+                        "op" => "i",
+                        "ns" => "foo.bar",
+                        "o" => {
+                            "_id" => 1
+                        }
+                    }
+                ],
+                "prepare" => true
+            },
+            "lsid" => {
+                "id" => 1
+            },
+            "txnNumber" => 2i64,
+            "prevOpTime" => {
+                "ts" => (Bson::TimeStamp((1567578725 << 32) + 1)),
+                "t" => 1i64
+            }
+        };
+        let operation = Operation::new(&doc).unwrap();
+
+        assert_eq!(operation,
+                   Operation::ApplyOps {
+                       id: None,
+                       optime: OpTime::new(1567578725, 3),
+                       timestamp: Utc.timestamp_opt(1567578725, 0).unwrap(),
+                       term: Some(1),
+                       wall: None,
+                       version: Some(2),
+                       transaction: Some(Transaction {
+                           session_id: doc! { "id" => 1 },
+                           txn_number: 2,
+                           statement_id: None,
+                           prev_optime: Some(OpTime::new(1567578725, 1)),
+                           prev_term: Some(1),
+                           state: Some(TransactionState::Prepared),
+                       }),
+                       namespace: "admin.$cmd".into(),
+                       uuid: None,
+                       operations: vec![Operation::Insert {
+                                            id: None,
+                                            optime: OpTime::new(1567578725, 3),
+                                            timestamp: Utc.timestamp_opt(1567578725, 0).unwrap(),
+                                            term: None,
+                                            wall: None,
+                                            version: None,
+                                            transaction: None,
+                                            namespace: "foo.bar".into(),
+                                            uuid: None,
+                                            document: doc! { "_id" => 1 },
+                                        }],
+                   });
+    }
 }
@@ -1,13 +1,31 @@
 //! The oplog module is responsible for building an iterator over a MongoDB replica set oplog with
 //! any optional filtering criteria applied.
+//!
+//! Operations are read either by tailing the oplog directly or from a change stream, as chosen
+//! with `OplogBuilder::source`.
 
-use bson::Document;
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bson::spec::BinarySubtype;
+use bson::{Bson, Document};
+use chrono::{DateTime, Utc};
 use mongodb::coll::options::{FindOptions, CursorType};
+use mongodb::common::ReadPreference;
 use mongodb::cursor::Cursor;
 use mongodb::db::ThreadedDatabase;
 use mongodb::{Client, ThreadedClient};
+use uuid::Uuid;
 
-use {Operation, Result};
+use change_stream;
+use namespace::{self, NamespacePattern};
+use operation::{optional, uuid};
+use transaction::{Transaction, TransactionState};
+use {CheckpointPolicy, CheckpointStore, Error, OpTime, Operation, OperationKind, RawOperation,
+     Result};
 
 /// Oplog represents a MongoDB replica set oplog.
 ///
@@ -15,23 +33,71 @@ use {Operation, Result};
 /// as they are read from the server. This will effectively iterate forever as it will await new
 /// operations.
 ///
-/// Any errors raised while tailing the oplog (e.g. a connectivity issue) will cause the iteration
-/// to end.
+/// Any errors raised while tailing the oplog (e.g. a connectivity issue or an entry that cannot
+/// be decoded) will cause the iteration to end. Use `try_iter` to receive these errors instead.
+///
+/// If built with a `Backoff` policy via `OplogBuilder::resume`, the `Oplog` will instead reopen
+/// its cursor after a database error or once the server stops returning entries for it (e.g. the
+/// cursor was killed), continuing from the last entry it read.
+///
+/// If built with a `CheckpointStore` via `OplogBuilder::checkpoint`, the `Oplog` will commit the
+/// position of each operation to the store once the following operation is requested.
+///
+/// If built with namespaces to scan via `OplogBuilder::initial_sync`, the `Oplog` will first yield
+/// an insert for every document in those namespaces.
+///
+/// If built with `OplogBuilder::update_lookup`, the `Oplog` will look up the current version of
+/// each updated document in batches.
 pub struct Oplog {
-    /// The internal MongoDB cursor for the current position in the oplog.
-    cursor: Cursor,
+    /// The connection used to open the cursor, scan namespaces and look up documents.
+    connection: Box<dyn Connection>,
+    /// The query used to open the cursor.
+    query: Query,
+    /// The position in the oplog from which the cursor was first opened, if any.
+    start: Option<Position>,
+    /// The internal cursor for the current position in the oplog.
+    cursor: Box<dyn Entries>,
+    /// The position of the last entry read from the cursor, if any.
+    last_optime: Option<OpTime>,
+    /// The position of the last entry returned, if any.
+    ///
+    /// This trails `last_optime` while entries read ahead to look up updates are pending.
+    returned: Option<OpTime>,
+    /// The position of the last entry returned after which no further entries share the same
+    /// position, if any.
+    ///
+    /// This trails `returned` when reading from a change stream, where every event in a
+    /// transaction shares its `clusterTime`.
+    completed: Option<OpTime>,
+    /// The session, transaction number and last completed position before the first entry of each
+    /// multi-document transaction returned but not yet committed or aborted.
+    ///
+    /// Checkpoints are held before the earliest of these so that resuming rereads every entry of
+    /// a transaction still pending.
+    transactions: Vec<(Document, i64, Option<OpTime>)>,
+    /// The resume token of the last change event read from a change stream, if any.
+    resume_token: Option<Document>,
+    /// The policy for resuming after a database error, if any.
+    backoff: Option<Backoff>,
+    /// The number of consecutive attempts made to resume since the last entry was read.
+    attempts: u32,
+    /// The number of consecutive polls of the cursor that returned without awaiting new entries.
+    empty_polls: u32,
+    /// The store for committing processed positions, if any.
+    checkpoint: Option<Checkpointer>,
+    /// The scan of namespaces yielded before tailing the oplog, if any.
+    sync: Option<InitialSync>,
+    /// The lookup of the current documents of updates, if any.
+    lookup: Option<UpdateLookup>,
 }
 
 impl Iterator for Oplog {
     type Item = Operation;
 
     fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            match self.cursor.next() {
-                Some(Ok(document)) => return Operation::new(&document).ok(),
-                Some(Err(_)) => return None,
-                None => continue,
-            }
+        match self.next_operation() {
+            Some(Ok(operation)) => Some(operation),
+            _ => None,
         }
     }
 }
@@ -58,6 +124,516 @@ impl Oplog {
     pub fn new(client: &Client) -> Result<Oplog> {
         OplogBuilder::new(client).build()
     }
+
+    /// Returns an iterator that yields a `Result` for every entry in the oplog.
+    ///
+    /// Unlike iterating over the `Oplog` itself, errors do not end the iteration: database errors
+    /// are returned as `Error::Database` and entries that cannot be converted into an `Operation`
+    /// are returned as the relevant decoding error, leaving the caller to decide whether to carry
+    /// on.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # extern crate mongodb;
+    /// # extern crate oplog;
+    /// use mongodb::{Client, ThreadedClient};
+    /// use oplog::{Error, Oplog};
+    ///
+    /// # fn main() {
+    /// let client = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+    ///
+    /// if let Ok(mut oplog) = Oplog::new(&client) {
+    ///     for result in oplog.try_iter() {
+    ///         match result {
+    ///             Ok(operation) => println!("{}", operation),
+    ///             Err(Error::Database(err)) => {
+    ///                 eprintln!("Lost connection to the oplog: {}", err);
+    ///                 break;
+    ///             }
+    ///             Err(err) => eprintln!("Skipping invalid entry: {}", err),
+    ///         }
+    ///     }
+    /// }
+    /// # }
+    /// ```
+    pub fn try_iter(&mut self) -> TryIter<'_> {
+        TryIter { oplog: self }
+    }
+
+    /// Returns an iterator that yields every entry in the oplog without decoding it.
+    ///
+    /// Each `RawOperation` keeps the original document, including any fields that an `Operation`
+    /// does not, and can be decoded with `RawOperation::operation`. As with `try_iter`, database
+    /// errors do not end the iteration.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # extern crate mongodb;
+    /// # extern crate oplog;
+    /// use mongodb::{Client, ThreadedClient};
+    /// use oplog::Oplog;
+    ///
+    /// # fn main() {
+    /// let client = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+    ///
+    /// if let Ok(mut oplog) = Oplog::new(&client) {
+    ///     for raw in oplog.raw_iter().filter_map(Result::ok) {
+    ///         match raw.operation() {
+    ///             Ok(operation) => println!("{}", operation),
+    ///             Err(err) => eprintln!("Failed to decode {}: {}", raw.document(), err),
+    ///         }
+    ///     }
+    /// }
+    /// # }
+    /// ```
+    pub fn raw_iter(&mut self) -> RawIter<'_> {
+        RawIter { oplog: self }
+    }
+
+    /// Commits the position of the last operation read to the `CheckpointStore`, if any.
+    ///
+    /// This is done automatically according to the `CheckpointPolicy` given to
+    /// `OplogBuilder::checkpoint` but can be called explicitly, e.g. before shutting down, to
+    /// avoid processing operations again when the oplog is next built.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # extern crate mongodb;
+    /// # extern crate oplog;
+    /// use mongodb::{Client, ThreadedClient};
+    /// use oplog::{CheckpointPolicy, FileCheckpointStore, OplogBuilder};
+    ///
+    /// # fn main() {
+    /// let client = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+    /// let store = FileCheckpointStore::new("oplog.checkpoint");
+    ///
+    /// if let Ok(mut oplog) = OplogBuilder::new(&client)
+    ///                                     .checkpoint(store, CheckpointPolicy::default())
+    ///                                     .build() {
+    ///     for operation in oplog.by_ref().take(100) {
+    ///         // Do something with operation...
+    ///     }
+    ///
+    ///     oplog.commit().expect("Failed to commit checkpoint.");
+    /// }
+    /// # }
+    /// ```
+    pub fn commit(&mut self) -> Result<()> {
+        match (self.committable(), self.checkpoint.as_mut()) {
+            (Some(optime), Some(checkpoint)) => {
+                checkpoint.processed = Some(optime);
+                checkpoint.commit()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns whether the oplog will scan namespaces before tailing, i.e. it was built with an
+    /// initial sync and did not start from any other position.
+    pub(crate) fn is_syncing(&self) -> bool {
+        self.sync.is_some()
+    }
+
+        /// Returns the next entry in the oplog, awaiting new entries as necessary.
+    fn next_operation(&mut self) -> Option<Result<Operation>> {
+        loop {
+            if let Some(result) = self.poll_operation() {
+                return Some(result);
+            }
+        }
+    }
+
+    /// Returns the next entry in the oplog if one arrives before the server stops awaiting new
+    /// entries, otherwise `None`.
+    pub(crate) fn poll_operation(&mut self) -> Option<Result<Operation>> {
+        if let Err(err) = self.process_checkpoint() {
+            return Some(Err(err));
+        }
+
+        let (result, position) = if self.lookup.is_some() {
+            self.poll_looked_up()?
+        } else {
+            self.read_decoded()?
+        };
+
+        self.record(position, result.as_ref().ok().and_then(Operation::transaction));
+
+        Some(result)
+    }
+
+    /// Returns the next entry in the oplog and its position with the current documents of its
+    /// updates, reading ahead to look up the documents of several updates at once.
+    fn poll_looked_up(&mut self) -> Option<(Result<Operation>, Option<OpTime>)> {
+        let batch_size = match self.lookup {
+            Some(ref mut lookup) => {
+                if let Some(entry) = lookup.pending.pop_front() {
+                    return Some(entry);
+                }
+
+                lookup.batch_size
+            }
+            None => return None,
+        };
+
+        let mut batch = vec![self.read_decoded()?];
+        let mut updates = batch[0].0.as_ref().map_or(0, lookups);
+
+        // Only entries already received are read ahead so that no update is held back while the
+        // server awaits new entries.
+        while updates > 0 && updates < batch_size && self.cursor.is_buffered() {
+            let entry = match self.read_decoded() {
+                Some(entry) => entry,
+                None => break,
+            };
+            let is_err = entry.0.is_err();
+            updates += entry.0.as_ref().map_or(0, lookups);
+            batch.push(entry);
+
+            if is_err {
+                break;
+            }
+        }
+
+        let lookup = self.lookup.as_mut()?;
+        if updates > 0 {
+            let mut operations = batch.iter_mut()
+                .filter_map(|entry| entry.0.as_mut().ok())
+                .collect::<Vec<_>>();
+
+            if let Err(err) = lookup.look_up(&*self.connection, &mut operations) {
+                lookup.pending.push_back((Err(err), None));
+            }
+        }
+        lookup.pending.extend(batch);
+
+        lookup.pending.pop_front()
+    }
+
+    /// Returns the next decoded entry in the oplog and its position if one arrives before the
+    /// server stops awaiting new entries, otherwise `None`.
+    fn read_decoded(&mut self) -> Option<(Result<Operation>, Option<OpTime>)> {
+        let (result, position) = self.read_raw()?;
+
+        Some((result.and_then(|raw| raw.operation()), position))
+    }
+
+    /// Returns the next undecoded entry in the oplog, awaiting new entries as necessary.
+    fn next_raw(&mut self) -> Option<Result<RawOperation>> {
+        loop {
+            if let Some(result) = self.poll_raw() {
+                return Some(result);
+            }
+        }
+    }
+
+    /// Returns the next undecoded entry in the oplog if one arrives before the server stops
+    /// awaiting new entries, otherwise `None`.
+    fn poll_raw(&mut self) -> Option<Result<RawOperation>> {
+        if let Err(err) = self.process_checkpoint() {
+            return Some(Err(err));
+        }
+
+        let (result, position) = self.read_raw()?;
+        let transaction = match result {
+            Ok(ref raw) if raw.source() == Source::Oplog => {
+                Transaction::from_entry(raw.document()).unwrap_or(None)
+            }
+            _ => None,
+        };
+        self.record(position, transaction.as_ref());
+
+        Some(result)
+    }
+
+    /// Records the position of an entry being returned along with whether it leaves a
+    /// multi-document transaction pending.
+    fn record(&mut self, position: Option<OpTime>, transaction: Option<&Transaction>) {
+        if let Some(transaction) = transaction {
+            let index = self.transactions.iter().position(|&(ref session_id, txn_number, _)| {
+                *session_id == transaction.session_id && txn_number == transaction.txn_number
+            });
+
+            match (transaction.state, index) {
+                (Some(TransactionState::Partial), None) |
+                (Some(TransactionState::Prepared), None) => {
+                    self.transactions.push((transaction.session_id.clone(),
+                                            transaction.txn_number,
+                                            self.completed));
+                }
+                (Some(TransactionState::Committed), Some(index)) |
+                (Some(TransactionState::Aborted), Some(index)) => {
+                    self.transactions.remove(index);
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(position) = position {
+            if self.returned != Some(position) {
+                self.completed = match self.query {
+                    Query::Oplog(_) => Some(position),
+                    Query::ChangeStream(..) => self.returned,
+                };
+            }
+            self.returned = Some(position);
+        }
+    }
+
+    /// Returns the position up to which every operation returned may be checkpointed, if any.
+    ///
+    /// This is the last completed position unless a transaction is pending, in which case it is
+    /// the last completed position before that transaction's first entry.
+    fn committable(&self) -> Option<OpTime> {
+        self.transactions
+            .iter()
+            .fold(self.completed, |position, &(_, _, start)| cmp::min(position, start))
+    }
+
+    /// Records the last operation returned as processed now that the next one is requested.
+    fn process_checkpoint(&mut self) -> Result<()> {
+        match (self.committable(), self.checkpoint.as_mut()) {
+            (Some(optime), Some(checkpoint)) => checkpoint.process(optime),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the next undecoded entry in the oplog and, if read while tailing, its position if
+    /// one arrives before the server stops awaiting new entries, otherwise `None`.
+    fn read_raw(&mut self) -> Option<(Result<RawOperation>, Option<OpTime>)> {
+        if let Some(result) = self.poll_scan() {
+            return Some((result, None));
+        }
+
+        let polled = Instant::now();
+
+        match self.cursor.next() {
+            Some(Ok(document)) => {
+                self.attempts = 0;
+                self.empty_polls = 0;
+
+                let mut raw = RawOperation::new(document, self.query.source());
+                let position = raw.optime().ok();
+                if position.is_some() {
+                    self.last_optime = position;
+                }
+                if let Query::ChangeStream(..) = self.query {
+                    if let Ok(token) = raw.document().get_document("_id") {
+                        self.resume_token = Some(token.clone());
+                    }
+                }
+
+                if self.is_duplicate(&mut raw) {
+                    return None;
+                }
+
+                Some((Ok(raw), position))
+            }
+            Some(Err(err)) => {
+                self.empty_polls = 0;
+
+                self.resume(err).err().map(|err| (Err(err), None))
+            }
+            None => {
+                // As the driver ignores the cursor id returned by the server, a killed cursor
+                // returns no entries without awaiting new ones rather than an error.
+                if polled.elapsed() >= MIN_AWAIT {
+                    self.empty_polls = 0;
+                    return None;
+                }
+
+                self.empty_polls += 1;
+                if self.empty_polls < DEAD_CURSOR_POLLS {
+                    return None;
+                }
+
+                self.empty_polls = 0;
+                let err = Error::Database(mongodb::Error::CursorNotFoundError);
+
+                self.resume(err).err().map(|err| (Err(err), None))
+            }
+        }
+    }
+
+    /// Returns an insert for the next document scanned by an initial sync, if any.
+    ///
+    /// Once every namespace has been scanned, the position of the most recent operation is
+    /// recorded so that inserts of scanned documents up to that position can be skipped.
+    fn poll_scan(&mut self) -> Option<Result<RawOperation>> {
+        let sync = match self.sync {
+            Some(ref mut sync) if sync.end.is_none() => sync,
+            _ => return None,
+        };
+
+        loop {
+            if sync.scan.is_none() {
+                let namespace = match sync.namespaces.pop_front() {
+                    Some(namespace) => namespace,
+                    None => {
+                        return match self.connection.head(&self.query) {
+                            Ok(end) => {
+                                sync.end = Some(end.unwrap_or(sync.optime));
+                                None
+                            }
+                            Err(err) => Some(Err(err)),
+                        };
+                    }
+                };
+                let uuid = match self.connection.collection_uuid(&namespace) {
+                    Ok(uuid) => uuid,
+                    Err(err) => return Some(Err(err)),
+                };
+                let pipeline = self.query.scan_pipeline(&namespace, uuid, sync.optime);
+
+                match self.connection.aggregate(&namespace, pipeline) {
+                    Ok(cursor) => sync.scan = Some((namespace, uuid, cursor)),
+                    Err(err) => return Some(Err(err)),
+                }
+            }
+
+            let next = match sync.scan {
+                Some((ref namespace, uuid, ref mut cursor)) => {
+                    cursor.next().map(|result| (namespace.clone(), uuid, result))
+                }
+                None => None,
+            };
+
+            match next {
+                Some((namespace, uuid, Ok(document))) => {
+                    if let Some(id) = document.get("_id") {
+                        sync.seen.insert((namespace.clone(), id.to_string()));
+                    }
+
+                    let operation = Operation::Insert {
+                        id: None,
+                        optime: sync.optime,
+                        timestamp: sync.optime.to_datetime(),
+                        term: None,
+                        wall: None,
+                        version: None,
+                        transaction: None,
+                        namespace,
+                        uuid,
+                        document,
+                    };
+
+                    return Some(Ok(RawOperation::new(operation.to_document(), Source::Oplog)));
+                }
+                Some((_, _, Err(err))) => return Some(Err(err)),
+                None => sync.scan = None,
+            }
+        }
+    }
+
+    /// Returns whether the given entry read while tailing is an insert of a document already
+    /// yielded by an initial sync, removing any such inserts from an `applyOps` command.
+    ///
+    /// An `applyOps` command is never skipped, even if all of its operations are removed, so that
+    /// the transaction it belongs to is still committed. Deduplication ends once the oplog passes
+    /// the position recorded at the end of the scan.
+    fn is_duplicate(&mut self, raw: &mut RawOperation) -> bool {
+        let end = match self.sync {
+            Some(InitialSync { end: Some(end), .. }) => end,
+            _ => return false,
+        };
+
+        match raw.optime() {
+            Ok(optime) if optime <= end => {}
+            _ => {
+                self.sync = None;
+                return false;
+            }
+        }
+
+        let (sync, operation) = match (self.sync.as_mut(), raw.operation()) {
+            (Some(sync), Ok(operation)) => (sync, operation),
+            _ => return false,
+        };
+
+        let operations = match operation {
+            Operation::ApplyOps { operations, .. } => operations,
+            operation => return sync.is_duplicate(&operation),
+        };
+        let mut duplicates = operations.iter().map(|operation| sync.is_duplicate(operation));
+        let mut document = raw.document().clone();
+
+        if let Some(&mut Bson::Document(ref mut o)) = document.get_mut("o") {
+            if let Some(&mut Bson::Array(ref mut entries)) = o.get_mut("applyOps") {
+                entries.retain(|_| !duplicates.next().unwrap_or(false));
+            }
+        }
+        *raw = RawOperation::new(document, raw.source());
+
+        false
+    }
+
+    /// Attempts to reopen the cursor after the given error according to the backoff policy.
+    ///
+    /// Returns the most recent error if there is no policy or it has run out of attempts.
+    fn resume(&mut self, mut err: Error) -> Result<()> {
+        loop {
+            let delay = match self.backoff {
+                Some(ref backoff) => backoff.delay(self.attempts),
+                None => None,
+            };
+
+            match delay {
+                Some(delay) => {
+                    thread::sleep(delay);
+                    self.attempts += 1;
+
+                    let position = self.resume_position();
+
+                    match self.connection
+                        .open(&self.query, position, self.resume_token.as_ref()) {
+                        Ok(cursor) => {
+                            self.cursor = cursor;
+                            return Ok(());
+                        }
+                        Err(resume_err) => err = resume_err,
+                    }
+                }
+                None => return Err(err),
+            }
+        }
+    }
+
+    /// Returns the position from which to reopen the cursor.
+    fn resume_position(&self) -> Option<Position> {
+        self.last_optime.map(Position::After).or(self.start)
+    }
+}
+
+/// An iterator over the `Result` of reading each entry in an `Oplog`.
+///
+/// This is returned by `Oplog::try_iter`.
+pub struct TryIter<'a> {
+    oplog: &'a mut Oplog,
+}
+
+impl<'a> Iterator for TryIter<'a> {
+    type Item = Result<Operation>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.oplog.next_operation()
+    }
+}
+
+/// An iterator over the `Result` of reading each entry in an `Oplog` without decoding it.
+///
+/// This is returned by `Oplog::raw_iter`.
+pub struct RawIter<'a> {
+    oplog: &'a mut Oplog,
+}
+
+impl<'a> Iterator for RawIter<'a> {
+    type Item = Result<RawOperation>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.oplog.next_raw()
+    }
 }
 
 /// A builder for an `Oplog`.
@@ -69,7 +645,17 @@ impl Oplog {
 #[derive(Clone)]
 pub struct OplogBuilder<'a> {
     client: &'a Client,
+    source: Source,
     filter: Option<Document>,
+    include: Vec<NamespacePattern>,
+    include_uuids: Vec<Uuid>,
+    exclude: Vec<NamespacePattern>,
+    kinds: Option<Vec<OperationKind>>,
+    start: Option<Position>,
+    backoff: Option<Backoff>,
+    checkpoint: Option<(Arc<dyn CheckpointStore>, CheckpointPolicy)>,
+    initial_sync: Vec<String>,
+    update_lookup: Option<(ReadPreference, usize)>,
 }
 
 impl<'a> OplogBuilder<'a> {
@@ -95,28 +681,139 @@ impl<'a> OplogBuilder<'a> {
     /// ```
     pub fn new(client: &'a Client) -> OplogBuilder<'a> {
         OplogBuilder {
-            client: client,
+            client,
+            source: Source::Oplog,
             filter: None,
+            include: Vec::new(),
+            include_uuids: Vec::new(),
+            exclude: Vec::new(),
+            kinds: None,
+            start: None,
+            backoff: None,
+            checkpoint: None,
+            initial_sync: Vec::new(),
+            update_lookup: None,
         }
     }
 
     /// Executes the query and builds the `Oplog`.
+    ///
+    /// If a `CheckpointStore` was given with `checkpoint` and it contains a committed position,
+    /// the oplog starts after that position instead of any position given with `start_at`,
+    /// `start_after` or `start_at_time`.
+    ///
+    /// Namespaces given with `initial_sync` are only scanned if the oplog has no other position to
+    /// start from. Returns `Error::InvalidOperation` if any of them is not of the form
+    /// `database.collection`.
     pub fn build(&self) -> Result<Oplog> {
-        let coll = self.client.db("local").collection("oplog.rs");
+        self.build_with(Box::new(self.client.clone()))
+    }
 
-        let mut opts = FindOptions::new();
-        opts.cursor_type = CursorType::TailableAwait;
-        opts.no_cursor_timeout = true;
+    /// Builds the `Oplog`, opening its cursor with the given connection.
+    fn build_with(&self, connection: Box<dyn Connection>) -> Result<Oplog> {
+        let mut start = self.start;
+        let mut checkpoint = None;
+        let mut sync = None;
+
+        if let Some((ref store, ref policy)) = self.checkpoint {
+            if let Some(optime) = store.load()? {
+                start = Some(Position::After(optime));
+            }
+
+            checkpoint = Some(Checkpointer::new(store.clone(), policy.clone()));
+        }
+
+        for namespace in &self.initial_sync {
+            match namespace::split(namespace) {
+                Some((database, collection)) if !database.is_empty() && !collection.is_empty() => {}
+                _ => return Err(Error::InvalidOperation),
+            }
+        }
 
-        let cursor = coll.find(self.filter.clone(), Some(opts))?;
+        let query = match self.source {
+            Source::Oplog => Query::Oplog(self.query_filter()),
+            Source::ChangeStream => {
+                Query::ChangeStream(self.pipeline(), self.update_lookup.is_some())
+            }
+        };
+
+        if !self.initial_sync.is_empty() && start.is_none() {
+            let head = connection.head(&query)?;
+            start = head.map(Position::After);
+            sync = Some(InitialSync {
+                namespaces: self.initial_sync.iter().cloned().collect(),
+                scan: None,
+                optime: head.unwrap_or_else(|| OpTime::new(0, 0)),
+                end: None,
+                seen: HashSet::new(),
+            });
+        }
+
+        let cursor = connection.open(&query, start, None)?;
+
+        // Change streams look up the documents of updates themselves.
+        let lookup = match (self.source, self.update_lookup.as_ref()) {
+            (Source::Oplog, Some(&(ref read_preference, batch_size))) => {
+                Some(UpdateLookup {
+                    read_preference: read_preference.clone(),
+                    batch_size: cmp::max(batch_size, 1),
+                    pending: VecDeque::new(),
+                })
+            }
+            _ => None,
+        };
+
+        Ok(Oplog {
+            connection,
+            query,
+            start,
+            cursor,
+            last_optime: None,
+            returned: None,
+            completed: None,
+            transactions: Vec::new(),
+            resume_token: None,
+            backoff: self.backoff.clone(),
+            attempts: 0,
+            empty_polls: 0,
+            checkpoint,
+            sync,
+            lookup,
+        })
+    }
 
-        Ok(Oplog { cursor: cursor })
+    /// Choose where to read operations from.
+    ///
+    /// This is `Source::Oplog` by default.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # extern crate mongodb;
+    /// # extern crate oplog;
+    /// use mongodb::{Client, ThreadedClient};
+    /// use oplog::{OplogBuilder, Source};
+    ///
+    /// # fn main() {
+    /// let client = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+    ///
+    /// if let Ok(oplog) = OplogBuilder::new(&client).source(Source::ChangeStream).build() {
+    ///     // Do something with operations from the change stream.
+    /// }
+    /// # }
+    /// ```
+    pub fn source(&mut self, source: Source) -> &mut OplogBuilder<'a> {
+        self.source = source;
+        self
     }
 
     /// Provide an optional filter for the oplog.
     ///
     /// This is empty by default so all operations are returned.
     ///
+    /// When reading from a `Source::ChangeStream`, the filter is matched against change events
+    /// rather than oplog entries.
+    ///
     /// # Example
     ///
     /// ```rust,no_run
@@ -140,4 +837,2042 @@ impl<'a> OplogBuilder<'a> {
         self.filter = filter;
         self
     }
+
+    /// Only include operations on the given namespace, e.g. `foo.bar`.
+    ///
+    /// The namespace may contain `*` wildcards to match any sequence of characters, e.g. `foo.*`
+    /// or `*.users`. Calling this (or any other `include_` method) multiple times includes
+    /// operations matching any of the given patterns.
+    ///
+    /// `applyOps` commands are included if any of their operations are on a matching namespace.
+    /// Note that commands such as creating or dropping a collection are recorded against the
+    /// `$cmd` namespace of their database (e.g. `foo.$cmd`) so use `include_database` to include
+    /// them.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # extern crate mongodb;
+    /// # extern crate oplog;
+    /// use mongodb::{Client, ThreadedClient};
+    /// use oplog::OplogBuilder;
+    ///
+    /// # fn main() {
+    /// let client = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+    ///
+    /// if let Ok(oplog) = OplogBuilder::new(&client)
+    ///                                 .include_namespace("foo.bar")
+    ///                                 .include_namespace("baz.*")
+    ///                                 .build() {
+    ///     // Do something with operations on foo.bar and any collection in baz.
+    /// }
+    /// # }
+    /// ```
+    pub fn include_namespace(&mut self, namespace: &str) -> &mut OplogBuilder<'a> {
+        self.include.push(NamespacePattern::new(namespace));
+        self
+    }
+
+    /// Only include operations on any collection in the given database, including commands.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # extern crate mongodb;
+    /// # extern crate oplog;
+    /// use mongodb::{Client, ThreadedClient};
+    /// use oplog::OplogBuilder;
+    ///
+    /// # fn main() {
+    /// let client = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+    ///
+    /// if let Ok(oplog) = OplogBuilder::new(&client).include_database("foo").build() {
+    ///     // Do something with operations on the foo database.
+    /// }
+    /// # }
+    /// ```
+    pub fn include_database(&mut self, database: &str) -> &mut OplogBuilder<'a> {
+        self.include.push(NamespacePattern::Database(database.into()));
+        self
+    }
+
+    /// Only include operations on namespaces matching the given regular expression.
+    ///
+    /// The regular expression is evaluated by the MongoDB server.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # extern crate mongodb;
+    /// # extern crate oplog;
+    /// use mongodb::{Client, ThreadedClient};
+    /// use oplog::OplogBuilder;
+    ///
+    /// # fn main() {
+    /// let client = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+    ///
+    /// if let Ok(oplog) = OplogBuilder::new(&client).include_regex(r"^shard_\d+\.users$").build() {
+    ///     // Do something with operations on matching namespaces.
+    /// }
+    /// # }
+    /// ```
+    pub fn include_regex(&mut self, regex: &str) -> &mut OplogBuilder<'a> {
+        self.include.push(NamespacePattern::Regex(regex.into()));
+        self
+    }
+
+    /// Only include operations on the collection with the given UUID.
+    ///
+    /// Unlike its namespace, a collection's UUID is unchanged when it is renamed so this continues
+    /// to include operations on the collection after a `renameCollection` command. UUIDs are only
+    /// recorded by MongoDB 3.6 and later. Note that change events only identify collections by
+    /// UUID on servers reporting their `collectionUUID`, so no operations are included from a
+    /// `Source::ChangeStream` otherwise.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # extern crate mongodb;
+    /// # extern crate oplog;
+    /// # extern crate uuid;
+    /// use mongodb::{Client, ThreadedClient};
+    /// use oplog::OplogBuilder;
+    /// use uuid::Uuid;
+    ///
+    /// # fn main() {
+    /// let client = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+    /// let uuid = Uuid::parse_str("3b241101-e2bb-4255-8caf-4136c566a962").unwrap();
+    ///
+    /// if let Ok(oplog) = OplogBuilder::new(&client).include_uuid(uuid).build() {
+    ///     // Do something with operations on the collection, whatever its name.
+    /// }
+    /// # }
+    /// ```
+    pub fn include_uuid(&mut self, uuid: Uuid) -> &mut OplogBuilder<'a> {
+        self.include_uuids.push(uuid);
+        self
+    }
+
+    /// Exclude operations on the given namespace, e.g. `foo.bar`.
+    ///
+    /// As with `include_namespace`, the namespace may contain `*` wildcards. Exclusions take
+    /// precedence over inclusions.
+    ///
+    /// `applyOps` commands are excluded if all of their operations are on excluded namespaces, so
+    /// those that are not may still contain operations on excluded namespaces.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # extern crate mongodb;
+    /// # extern crate oplog;
+    /// use mongodb::{Client, ThreadedClient};
+    /// use oplog::OplogBuilder;
+    ///
+    /// # fn main() {
+    /// let client = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+    ///
+    /// if let Ok(oplog) = OplogBuilder::new(&client)
+    ///                                 .include_database("foo")
+    ///                                 .exclude_namespace("foo.sessions")
+    ///                                 .build() {
+    ///     // Do something with operations on foo except foo.sessions.
+    /// }
+    /// # }
+    /// ```
+    pub fn exclude_namespace(&mut self, namespace: &str) -> &mut OplogBuilder<'a> {
+        self.exclude.push(NamespacePattern::new(namespace));
+        self
+    }
+
+    /// Exclude operations on any collection in the given database, including commands.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # extern crate mongodb;
+    /// # extern crate oplog;
+    /// use mongodb::{Client, ThreadedClient};
+    /// use oplog::OplogBuilder;
+    ///
+    /// # fn main() {
+    /// let client = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+    ///
+    /// if let Ok(oplog) = OplogBuilder::new(&client).exclude_database("config").build() {
+    ///     // Do something with operations outside the config database.
+    /// }
+    /// # }
+    /// ```
+    pub fn exclude_database(&mut self, database: &str) -> &mut OplogBuilder<'a> {
+        self.exclude.push(NamespacePattern::Database(database.into()));
+        self
+    }
+
+    /// Exclude operations on namespaces matching the given regular expression.
+    ///
+    /// The regular expression is evaluated by the MongoDB server.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # extern crate mongodb;
+    /// # extern crate oplog;
+    /// use mongodb::{Client, ThreadedClient};
+    /// use oplog::OplogBuilder;
+    ///
+    /// # fn main() {
+    /// let client = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+    ///
+    /// if let Ok(oplog) = OplogBuilder::new(&client).exclude_regex(r"\.tmp_").build() {
+    ///     // Do something with operations on non-temporary collections.
+    /// }
+    /// # }
+    /// ```
+    pub fn exclude_regex(&mut self, regex: &str) -> &mut OplogBuilder<'a> {
+        self.exclude.push(NamespacePattern::Regex(regex.into()));
+        self
+    }
+
+    /// Only include operations of the given kinds.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # extern crate mongodb;
+    /// # extern crate oplog;
+    /// use mongodb::{Client, ThreadedClient};
+    /// use oplog::{OperationKind, OplogBuilder};
+    ///
+    /// # fn main() {
+    /// let client = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+    ///
+    /// if let Ok(oplog) = OplogBuilder::new(&client)
+    ///                                 .kinds(&[OperationKind::Insert, OperationKind::Delete])
+    ///                                 .build() {
+    ///     // Do something with inserts and deletes.
+    /// }
+    /// # }
+    /// ```
+    pub fn kinds(&mut self, kinds: &[OperationKind]) -> &mut OplogBuilder<'a> {
+        self.kinds = Some(kinds.to_vec());
+        self
+    }
+
+    /// Start the oplog at the operation with the given `OpTime`, inclusive.
+    ///
+    /// This is more efficient than filtering on `ts` with `filter` as it allows the server to
+    /// seek directly to the given position rather than scanning the entire oplog.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # extern crate mongodb;
+    /// # extern crate oplog;
+    /// use mongodb::{Client, ThreadedClient};
+    /// use oplog::{OpTime, OplogBuilder};
+    ///
+    /// # fn main() {
+    /// let client = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+    ///
+    /// if let Ok(oplog) = OplogBuilder::new(&client).start_at(OpTime::new(1479561394, 1)).build() {
+    ///     // Do something with oplog from the given operation onwards.
+    /// }
+    /// # }
+    /// ```
+    pub fn start_at(&mut self, optime: OpTime) -> &mut OplogBuilder<'a> {
+        self.start = Some(Position::At(optime));
+        self
+    }
+
+    /// Start the oplog immediately after the operation with the given `OpTime`.
+    ///
+    /// This is useful for continuing from the last operation processed (e.g. as returned by
+    /// `Operation::optime`) without processing it again.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # extern crate mongodb;
+    /// # extern crate oplog;
+    /// use mongodb::{Client, ThreadedClient};
+    /// use oplog::{OpTime, OplogBuilder};
+    ///
+    /// # fn main() {
+    /// let client = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+    /// let last = OpTime::new(1479561394, 1);
+    ///
+    /// if let Ok(oplog) = OplogBuilder::new(&client).start_after(last).build() {
+    ///     // Do something with oplog after the last operation.
+    /// }
+    /// # }
+    /// ```
+    pub fn start_after(&mut self, optime: OpTime) -> &mut OplogBuilder<'a> {
+        self.start = Some(Position::After(optime));
+        self
+    }
+
+    /// Start the oplog at the first operation at or after the given time.
+    ///
+    /// As operations are only recorded to the nearest second, any fraction of a second is
+    /// ignored.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # extern crate chrono;
+    /// # extern crate mongodb;
+    /// # extern crate oplog;
+    /// use chrono::{Duration, Utc};
+    /// use mongodb::{Client, ThreadedClient};
+    /// use oplog::OplogBuilder;
+    ///
+    /// # fn main() {
+    /// let client = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+    /// let an_hour_ago = Utc::now() - Duration::hours(1);
+    ///
+    /// if let Ok(oplog) = OplogBuilder::new(&client).start_at_time(an_hour_ago).build() {
+    ///     // Do something with the last hour of operations.
+    /// }
+    /// # }
+    /// ```
+    pub fn start_at_time(&mut self, time: DateTime<Utc>) -> &mut OplogBuilder<'a> {
+        let seconds = cmp::min(cmp::max(time.timestamp(), 0), i64::from(u32::MAX)) as u32;
+
+        self.start_at(OpTime::new(seconds, 0))
+    }
+
+    /// Provide an optional policy for resuming the oplog after a database error.
+    ///
+    /// When set, the `Oplog` remembers the timestamp of the last entry it read and, should its
+    /// cursor fail (e.g. due to a primary stepping down or a network error) or be killed, waits
+    /// according to the given `Backoff` before reopening the cursor from the entry after it.
+    /// Operations are neither repeated nor skipped when resuming.
+    ///
+    /// A cursor is considered killed once the server repeatedly returns no entries for it without
+    /// awaiting new ones. This is empty by default so any database error, including a killed
+    /// cursor, is returned to the caller.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # extern crate mongodb;
+    /// # extern crate oplog;
+    /// use mongodb::{Client, ThreadedClient};
+    /// use oplog::{Backoff, OplogBuilder};
+    ///
+    /// # fn main() {
+    /// let client = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+    ///
+    /// if let Ok(oplog) = OplogBuilder::new(&client).resume(Some(Backoff::default())).build() {
+    ///     // Do something with resumable oplog.
+    /// }
+    /// # }
+    /// ```
+    pub fn resume(&mut self, backoff: Option<Backoff>) -> &mut OplogBuilder<'a> {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Returns the filter combining the user-supplied filter with any namespace criteria.
+    fn query_filter(&self) -> Option<Document> {
+        let mut clauses = Vec::new();
+
+        if let Some(ref filter) = self.filter {
+            clauses.push(filter.clone());
+        }
+
+        let mut include = Vec::new();
+
+        if !self.include.is_empty() {
+            let namespaces = to_bson(&self.include);
+
+            include.push(doc! { "ns" => { "$in" => (namespaces.clone()) } });
+            include.push(doc! { "o.applyOps.ns" => { "$in" => namespaces } });
+        }
+
+        if !self.include_uuids.is_empty() {
+            let uuids = uuids_to_bson(&self.include_uuids);
+
+            include.push(doc! { "ui" => { "$in" => (uuids.clone()) } });
+            include.push(doc! { "o.applyOps.ui" => { "$in" => uuids } });
+        }
+
+        clauses.extend(or(include));
+
+        if !self.exclude.is_empty() {
+            let exclude = to_bson(&self.exclude);
+
+            clauses.push(doc! { "ns" => { "$nin" => (exclude.clone()) } });
+            clauses.push(doc! {
+                "$or" => [
+                    { "o.applyOps" => { "$exists" => false } },
+                    { "o.applyOps" => { "$elemMatch" => { "ns" => { "$nin" => exclude } } } }
+                ]
+            });
+        }
+
+        if let Some(ref kinds) = self.kinds {
+            clauses.push(kinds_query(kinds));
+        }
+
+        and(clauses)
+    }
+
+    /// Returns the change stream pipeline stages applying the user-supplied filter and any
+    /// namespace and kind criteria.
+    ///
+    /// As change events describe their namespace as separate database and collection fields, the
+    /// full namespace is computed in a temporary field to match against.
+    fn pipeline(&self) -> Vec<Document> {
+        let mut stages = Vec::new();
+        let namespaces = !self.include.is_empty() || !self.exclude.is_empty();
+
+        if namespaces {
+            stages.push(doc! {
+                "$addFields" => {
+                    NAMESPACE_FIELD => {
+                        "$concat" => [
+                            "$ns.db",
+                            ".",
+                            { "$ifNull" => ["$ns.coll", { "$literal" => "$cmd" }] }
+                        ]
+                    }
+                }
+            });
+        }
+
+        let mut clauses = Vec::new();
+        let mut include = Vec::new();
+
+        if !self.include.is_empty() {
+            include.push(doc! { NAMESPACE_FIELD => { "$in" => (to_bson(&self.include)) } });
+        }
+
+        if !self.include_uuids.is_empty() {
+            include.push(doc! {
+                "collectionUUID" => { "$in" => (uuids_to_bson(&self.include_uuids)) }
+            });
+        }
+
+        clauses.extend(or(include));
+
+        if !self.exclude.is_empty() {
+            clauses.push(doc! { NAMESPACE_FIELD => { "$nin" => (to_bson(&self.exclude)) } });
+        }
+
+        if let Some(clause) = and(clauses) {
+            stages.push(doc! { "$match" => clause });
+        }
+
+        if namespaces {
+            stages.push(doc! { "$project" => { NAMESPACE_FIELD => 0 } });
+        }
+
+        if let Some(ref kinds) = self.kinds {
+            let mut types = Vec::new();
+            for kind in kinds {
+                for operation_type in change_stream::operation_types(*kind) {
+                    let operation_type = Bson::String((*operation_type).into());
+
+                    if !types.contains(&operation_type) {
+                        types.push(operation_type);
+                    }
+                }
+            }
+
+            stages.push(doc! { "$match" => { "operationType" => { "$in" => types } } });
+        }
+
+        if let Some(ref filter) = self.filter {
+            stages.push(doc! { "$match" => (filter.clone()) });
+        }
+
+        stages
+    }
+
+    /// Provide a store to commit the position of processed operations to and resume from.
+    ///
+    /// When built, the oplog starts after the position last committed to the store, if any.
+    /// While iterating, each operation is considered processed once the next one is requested
+    /// and its position is committed according to the given `CheckpointPolicy`. Note that
+    /// checkpoints are only committed while the oplog is being iterated.
+    ///
+    /// Checkpoints never pass the first entry of a multi-document transaction that has not yet
+    /// committed or aborted, so that resuming rereads all of its operations (e.g. for
+    /// `Transactions` to reassemble).
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # extern crate mongodb;
+    /// # extern crate oplog;
+    /// use mongodb::{Client, ThreadedClient};
+    /// use oplog::{CheckpointPolicy, FileCheckpointStore, OplogBuilder};
+    ///
+    /// # fn main() {
+    /// let client = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+    /// let store = FileCheckpointStore::new("oplog.checkpoint");
+    ///
+    /// if let Ok(oplog) = OplogBuilder::new(&client)
+    ///                                 .checkpoint(store, CheckpointPolicy::default())
+    ///                                 .build() {
+    ///     // Do something with oplog.
+    /// }
+    /// # }
+    /// ```
+    pub fn checkpoint<S>(&mut self, store: S, policy: CheckpointPolicy) -> &mut OplogBuilder<'a>
+        where S: CheckpointStore + 'static
+    {
+        self.checkpoint = Some((Arc::new(store), policy));
+        self
+    }
+
+    /// Scan the given namespace before tailing the oplog, yielding an insert for each of its
+    /// documents.
+    ///
+    /// The position of the most recent operation is recorded before scanning and the oplog is
+    /// then tailed from that position so that no change made during the scan is missed. Inserts
+    /// of documents already yielded by the scan are skipped until the oplog reaches the position
+    /// recorded once the scan finishes; any other operations during the scan are yielded and, as
+    /// they are idempotent, may safely be applied to the scanned documents.
+    ///
+    /// Only documents whose inserts would match the oplog's filters are scanned, as the filters
+    /// are applied to each document as an insert by the server.
+    ///
+    /// Scanned inserts have the recorded position, the collection UUID if known and no other
+    /// metadata. The scan is skipped if the oplog starts from any other position, e.g. one
+    /// committed to a `CheckpointStore`, so a consumer that commits checkpoints only performs its
+    /// initial sync once. Note that the `_id` of every scanned document is kept in memory until
+    /// the scan has been caught up with, and that duplicate inserts are also removed from
+    /// `applyOps` commands, leaving the rest of their operations.
+    ///
+    /// When reading from `Source::ChangeStream`, the position is the cluster time reported by the
+    /// server (its `operationTime`) rather than the top of `local.oplog.rs`, so an initial sync
+    /// works on `mongos` and without access to the `local` database.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # extern crate mongodb;
+    /// # extern crate oplog;
+    /// use mongodb::{Client, ThreadedClient};
+    /// use oplog::OplogBuilder;
+    ///
+    /// # fn main() {
+    /// let client = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+    ///
+    /// if let Ok(oplog) = OplogBuilder::new(&client)
+    ///                                 .include_namespace("app.users")
+    ///                                 .initial_sync("app.users")
+    ///                                 .build() {
+    ///     for operation in oplog {
+    ///         // Do something with every user followed by every change to them...
+    ///     }
+    /// }
+    /// # }
+    /// ```
+    pub fn initial_sync(&mut self, namespace: &str) -> &mut OplogBuilder<'a> {
+        self.initial_sync.push(namespace.into());
+        self
+    }
+
+    /// Look up the current version of each updated document, setting the `full_document` of
+    /// updates.
+    ///
+    /// As soon as an update is read, the oplog reads ahead until it has the given number of
+    /// updates or has read every operation already received from the server, then fetches their
+    /// documents with one query per collection using the given read preference. The documents
+    /// are those current when the query runs so may include later changes, and are `None` if
+    /// since deleted. Updates within `applyOps` commands are looked up in the same way.
+    ///
+    /// If a lookup fails, its error is returned before the operations of the batch, which are
+    /// then returned without their documents.
+    ///
+    /// When reading from a `Source::ChangeStream`, the change stream is instead opened with
+    /// `fullDocument: "updateLookup"` so that the server looks up each document as it is read,
+    /// ignoring the given read preference and batch size.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # extern crate mongodb;
+    /// # extern crate oplog;
+    /// use mongodb::common::{ReadMode, ReadPreference};
+    /// use mongodb::{Client, ThreadedClient};
+    /// use oplog::{Operation, OplogBuilder};
+    ///
+    /// # fn main() {
+    /// let client = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+    /// let secondary = ReadPreference::new(ReadMode::SecondaryPreferred, None);
+    ///
+    /// if let Ok(oplog) = OplogBuilder::new(&client).update_lookup(secondary, 100).build() {
+    ///     for operation in oplog {
+    ///         if let Operation::Update { full_document: Some(document), .. } = operation {
+    ///             // Do something with the updated document...
+    ///         }
+    ///     }
+    /// }
+    /// # }
+    /// ```
+    pub fn update_lookup(&mut self,
+                         read_preference: ReadPreference,
+                         batch_size: usize)
+                         -> &mut OplogBuilder<'a> {
+        self.update_lookup = Some((read_preference, batch_size));
+        self
+    }
+}
+
+/// The source from which an `Oplog` reads operations.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Source {
+    /// Tail the `local.oplog.rs` collection of a replica set directly.
+    ///
+    /// This reports every operation, including no-ops and `applyOps` commands, but requires read
+    /// access to the `local` database.
+    Oplog,
+    /// Open a change stream over every database in the cluster (requires MongoDB 4.0 or later).
+    ///
+    /// This works on sharded clusters and without access to the `local` database but only
+    /// reports inserts, updates, replacements, deletes, drops, renames and dropped databases.
+    /// Operations have no `id` and replacements are reported as updates with the full document.
+    ///
+    /// Namespace filters match drops and renames against the collection affected rather than the
+    /// `$cmd` namespace of its database. When starting after a given `OpTime`, note that all
+    /// operations in a transaction share the same `OpTime` so resuming part-way through one is
+    /// only possible after a database error, using the change stream's own resume token. For the
+    /// same reason, a checkpoint only advances to an `OpTime` once an operation with a later one
+    /// is returned, so resuming from a checkpoint may repeat operations but never skips any.
+    ChangeStream,
+}
+
+/// A policy for how long to wait between attempts to resume an `Oplog`.
+///
+/// The delay starts at `initial` and doubles with each consecutive failed attempt up to `max`.
+/// Once an entry is successfully read from the oplog, the delay is reset.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Backoff {
+    /// The delay before the first attempt to resume.
+    pub initial: Duration,
+    /// The maximum delay between attempts.
+    pub max: Duration,
+    /// The maximum number of consecutive attempts before giving up, or `None` to retry forever.
+    pub retries: Option<u32>,
+}
+
+impl Backoff {
+    /// Returns the delay before the given attempt (counting from zero) or `None` if no more
+    /// attempts should be made.
+    fn delay(&self, attempt: u32) -> Option<Duration> {
+        if let Some(retries) = self.retries {
+            if attempt >= retries {
+                return None;
+            }
+        }
+
+        let factor = 1u32.checked_shl(attempt).unwrap_or(u32::MAX);
+        let delay = self.initial.checked_mul(factor).unwrap_or(self.max);
+
+        Some(cmp::min(delay, self.max))
+    }
+}
+
+impl Default for Backoff {
+    /// Returns a policy starting at 100 milliseconds, backing off to at most 30 seconds between
+    /// attempts and retrying forever.
+    fn default() -> Backoff {
+        Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(30),
+            retries: None,
+        }
+    }
+}
+
+/// The progress of an initial sync of an `Oplog`.
+struct InitialSync {
+    /// The namespaces still to be scanned.
+    namespaces: VecDeque<String>,
+    /// The namespace, collection UUID and documents of the current scan, if any.
+    scan: Option<(String, Option<Uuid>, Documents)>,
+    /// The position of the most recent operation before the scan began.
+    optime: OpTime,
+    /// The position of the most recent operation once the scan finished, if it has.
+    end: Option<OpTime>,
+    /// The namespace and `_id` of each document yielded by the scan.
+    seen: HashSet<(String, String)>,
+}
+
+impl InitialSync {
+    /// Returns whether the given operation is an insert of a document yielded by the scan.
+    ///
+    /// A delete of a scanned document ends the deduplication of its inserts so that a document
+    /// deleted and inserted again during the scan is not lost.
+    fn is_duplicate(&mut self, operation: &Operation) -> bool {
+        match *operation {
+            Operation::Insert { ref namespace, ref document, .. } => {
+                match document.get("_id") {
+                    Some(id) => self.seen.contains(&(namespace.clone(), id.to_string())),
+                    None => false,
+                }
+            }
+            Operation::Delete { ref namespace, ref query, .. } => {
+                if let Some(id) = query.get("_id") {
+                    self.seen.remove(&(namespace.clone(), id.to_string()));
+                }
+
+                false
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The lookup of the current documents of updates read from an `Oplog`.
+struct UpdateLookup {
+    /// The read preference of the lookup queries.
+    read_preference: ReadPreference,
+    /// The maximum number of updates to look up at once.
+    batch_size: usize,
+    /// The operations already read and looked up but not yet returned, with their positions.
+    pending: VecDeque<(Result<Operation>, Option<OpTime>)>,
+}
+
+impl UpdateLookup {
+    /// Sets the current document of each update in the given operations that needs one,
+    /// including those within `applyOps` commands.
+    fn look_up(&self,
+               connection: &dyn Connection,
+               operations: &mut [&mut Operation])
+               -> Result<()> {
+        let mut ids = HashMap::new();
+        for operation in operations.iter() {
+            update_ids(operation, &mut ids);
+        }
+
+        let mut documents = HashMap::new();
+        for (namespace, ids) in ids {
+            let filter = doc! { "_id" => { "$in" => ids } };
+
+            for document in connection.find(&namespace, filter, &self.read_preference)? {
+                let document = document?;
+                if let Some(id) = document.get("_id") {
+                    documents.insert((namespace.clone(), id.to_string()), document.clone());
+                }
+            }
+        }
+
+        for operation in operations.iter_mut() {
+            set_full_documents(operation, &documents);
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the number of updates in the given operation whose current documents should be looked
+/// up.
+fn lookups(operation: &Operation) -> usize {
+    match *operation {
+        Operation::Update { full_document: None, .. } => 1,
+        Operation::ApplyOps { ref operations, .. } => operations.iter().map(lookups).sum(),
+        _ => 0,
+    }
+}
+
+/// Adds the `_id` of each update in the given operation whose current document should be looked
+/// up to the given ids of its namespace.
+fn update_ids(operation: &Operation, ids: &mut HashMap<String, Vec<Bson>>) {
+    match *operation {
+        Operation::Update { ref namespace, ref query, full_document: None, .. } => {
+            if let Some(id) = query.get("_id") {
+                ids.entry(namespace.clone()).or_default().push(id.clone());
+            }
+        }
+        Operation::ApplyOps { ref operations, .. } => {
+            for operation in operations {
+                update_ids(operation, ids);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Sets the current document of each update in the given operation from the given documents by
+/// namespace and `_id`, or to `None` if missing.
+fn set_full_documents(operation: &mut Operation, documents: &HashMap<(String, String), Document>) {
+    match *operation {
+        Operation::Update { ref namespace, ref query, ref mut full_document, .. } => {
+            if let (None, Some(id)) = (full_document.as_ref(), query.get("_id")) {
+                *full_document = documents.get(&(namespace.clone(), id.to_string())).cloned();
+            }
+        }
+        Operation::ApplyOps { ref mut operations, .. } => {
+            for operation in operations {
+                set_full_documents(operation, documents);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Tracks the operations processed by an `Oplog` and commits their positions to a store.
+struct Checkpointer {
+    store: Arc<dyn CheckpointStore>,
+    policy: CheckpointPolicy,
+    /// The position of the last operation processed.
+    processed: Option<OpTime>,
+    /// The position last committed to the store.
+    committed: Option<OpTime>,
+    /// The number of operations processed since the last commit.
+    count: u32,
+    /// The time of the last commit.
+    last_commit: Instant,
+}
+
+impl Checkpointer {
+    fn new(store: Arc<dyn CheckpointStore>, policy: CheckpointPolicy) -> Checkpointer {
+        Checkpointer {
+            store,
+            policy,
+            processed: None,
+            committed: None,
+            count: 0,
+            last_commit: Instant::now(),
+        }
+    }
+
+    /// Records the operation at the given position as processed, committing if the policy is met.
+    ///
+    /// This is also called with the same position while awaiting new operations so that the last
+    /// position is committed once the interval elapses, even if no other operation arrives.
+    fn process(&mut self, optime: OpTime) -> Result<()> {
+        if self.processed != Some(optime) {
+            self.processed = Some(optime);
+            self.count += 1;
+        }
+
+        let operations_due = self.policy
+            .operations
+            .is_some_and(|operations| self.count >= operations);
+        let interval_due = self.policy
+            .interval
+            .is_some_and(|interval| self.last_commit.elapsed() >= interval);
+
+        if operations_due || interval_due {
+            self.commit()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Commits the last processed position to the store if it has changed.
+    fn commit(&mut self) -> Result<()> {
+        if let Some(optime) = self.processed {
+            if self.committed != Some(optime) {
+                self.store.save(optime)?;
+                self.committed = Some(optime);
+            }
+        }
+
+        self.count = 0;
+        self.last_commit = Instant::now();
+
+        Ok(())
+    }
+}
+
+/// A position in the oplog from which to start reading.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Position {
+    /// Start at the operation with the given `OpTime`, inclusive.
+    At(OpTime),
+    /// Start at the operation following the given `OpTime`.
+    After(OpTime),
+}
+
+impl Position {
+    /// Returns the earliest `OpTime` that a change stream should start at.
+    fn to_optime(self) -> OpTime {
+        match self {
+            Position::At(optime) => optime,
+            Position::After(optime) => {
+                match optime.increment.checked_add(1) {
+                    Some(increment) => OpTime::new(optime.seconds, increment),
+                    None => OpTime::new(optime.seconds.saturating_add(1), 0),
+                }
+            }
+        }
+    }
+
+    /// Returns a query matching all operations from this position onwards.
+    fn to_query(self) -> Document {
+        match self {
+            Position::At(optime) => doc! { "ts" => { "$gte" => (Bson::from(optime)) } },
+            Position::After(optime) => doc! { "ts" => { "$gt" => (Bson::from(optime)) } },
+        }
+    }
+}
+
+/// The name of the temporary field holding the namespace of a change event while filtering.
+const NAMESPACE_FIELD: &str = "__oplogNamespace";
+
+/// The query used to open a cursor for an `Oplog`, depending on its source.
+enum Query {
+    /// A filter applied to the oplog, if any.
+    Oplog(Option<Document>),
+    /// The pipeline stages applied to a change stream and whether to look up the documents of
+    /// updates.
+    ChangeStream(Vec<Document>, bool),
+}
+
+impl Query {
+    /// Returns the source of the entries read by this query.
+    fn source(&self) -> Source {
+        match *self {
+            Query::Oplog(_) => Source::Oplog,
+            Query::ChangeStream(..) => Source::ChangeStream,
+        }
+    }
+
+    /// Returns the aggregation pipeline scanning the documents of the given namespace whose
+    /// inserts at the given position would match this query.
+    ///
+    /// Each document is reshaped into an oplog entry or change event so that the query applies
+    /// as it would while tailing, then reshaped back into the original document.
+    fn scan_pipeline(&self,
+                     namespace: &str,
+                     uuid: Option<Uuid>,
+                     optime: OpTime)
+                     -> Vec<Document> {
+        let ts = doc! { "$literal" => (Bson::from(optime)) };
+        let uuid = uuid.map(|uuid| doc! { "$literal" => (uuid_to_bson(&uuid)) });
+        let mut stages = Vec::new();
+
+        match *self {
+            Query::Oplog(ref filter) => {
+                let mut entry = doc! {
+                    "ts" => ts,
+                    "op" => { "$literal" => "i" },
+                    "ns" => { "$literal" => namespace },
+                    "o" => "$$ROOT"
+                };
+                if let Some(uuid) = uuid {
+                    entry.insert("ui", uuid);
+                }
+
+                stages.push(doc! { "$replaceRoot" => { "newRoot" => entry } });
+                if let Some(ref filter) = *filter {
+                    stages.push(doc! { "$match" => (filter.clone()) });
+                }
+                stages.push(doc! { "$replaceRoot" => { "newRoot" => "$o" } });
+            }
+            Query::ChangeStream(ref pipeline, _) => {
+                let (database, collection) = namespace::split(namespace).unwrap_or_default();
+                let mut event = doc! {
+                    "operationType" => { "$literal" => "insert" },
+                    "clusterTime" => ts,
+                    "ns" => {
+                        "db" => { "$literal" => database },
+                        "coll" => { "$literal" => collection }
+                    },
+                    "documentKey" => { "_id" => "$_id" },
+                    "fullDocument" => "$$ROOT"
+                };
+                if let Some(uuid) = uuid {
+                    event.insert("collectionUUID", uuid);
+                }
+
+                stages.push(doc! { "$replaceRoot" => { "newRoot" => event } });
+                stages.extend(pipeline.iter().cloned());
+                stages.push(doc! { "$replaceRoot" => { "newRoot" => "$fullDocument" } });
+            }
+        }
+
+        stages
+    }
+}
+
+/// The entries read from a cursor over the oplog or a change stream.
+///
+/// Unlike a `Cursor`, returning `None` does not end the entries: it means no new entry arrived
+/// while the server awaited one.
+trait Entries: Iterator<Item = Result<Document>> + Send {
+    /// Returns whether the next entry has already been received, so that reading it does not
+    /// await the server.
+    fn is_buffered(&self) -> bool;
+}
+
+/// The entries of a `Cursor`, read a batch at a time to know which have already been received.
+struct Batches {
+    cursor: Cursor,
+    /// The entries received but not yet read.
+    batch: VecDeque<Document>,
+}
+
+impl Iterator for Batches {
+    type Item = Result<Document>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.batch.is_empty() {
+            match self.cursor.drain_current_batch() {
+                Ok(batch) => self.batch.extend(batch),
+                Err(err) => return Some(Err(err.into())),
+            }
+        }
+
+        self.batch.pop_front().map(Ok)
+    }
+}
+
+impl Entries for Batches {
+    fn is_buffered(&self) -> bool {
+        !self.batch.is_empty()
+    }
+}
+
+/// The documents read from a cursor that is exhausted once it returns `None`.
+type Documents = Box<dyn Iterator<Item = Result<Document>> + Send>;
+
+/// The number of consecutive polls returning no entries without awaiting new ones after which a
+/// cursor is considered killed.
+const DEAD_CURSOR_POLLS: u32 = 3;
+
+/// The time within which a poll returning no entries cannot have awaited new ones, as the server
+/// awaits them for at least a second.
+const MIN_AWAIT: Duration = Duration::from_millis(500);
+
+/// The server from which an `Oplog` reads its entries.
+///
+/// This is implemented by `Client` and replaced in tests to read given entries instead.
+trait Connection: Send {
+    /// Returns a cursor over the entries matching the given query from the given position or,
+    /// for change streams, after the given resume token.
+    fn open(&self,
+            query: &Query,
+            position: Option<Position>,
+            resume_token: Option<&Document>)
+            -> Result<Box<dyn Entries>>;
+
+    /// Returns the position of the most recent operation read by the given query, if any.
+    ///
+    /// For change streams, this is the cluster time rather than the top of the oplog.
+    fn head(&self, query: &Query) -> Result<Option<OpTime>>;
+
+    /// Returns the UUID of the collection with the given namespace, if known.
+    fn collection_uuid(&self, namespace: &str) -> Result<Option<Uuid>>;
+
+    /// Returns the documents resulting from the given aggregation pipeline over the collection
+    /// with the given namespace.
+    fn aggregate(&self, namespace: &str, pipeline: Vec<Document>) -> Result<Documents>;
+
+    /// Returns the documents matching the given filter in the collection with the given
+    /// namespace, read with the given read preference.
+    fn find(&self,
+            namespace: &str,
+            filter: Document,
+            read_preference: &ReadPreference)
+            -> Result<Documents>;
+}
+
+impl Connection for Client {
+    fn open(&self,
+            query: &Query,
+            position: Option<Position>,
+            resume_token: Option<&Document>)
+            -> Result<Box<dyn Entries>> {
+        let cursor = match *query {
+            Query::Oplog(ref filter) => open_cursor(self, filter, position)?,
+            Query::ChangeStream(ref stages, update_lookup) => {
+                change_stream::open_cursor(self,
+                                           stages,
+                                           update_lookup,
+                                           resume_token,
+                                           position.map(Position::to_optime))?
+            }
+        };
+
+        Ok(Box::new(Batches {
+            cursor,
+            batch: VecDeque::new(),
+        }))
+    }
+
+    fn head(&self, query: &Query) -> Result<Option<OpTime>> {
+        match *query {
+            Query::Oplog(_) => head(self),
+            Query::ChangeStream(..) => change_stream::cluster_time(self),
+        }
+    }
+
+    fn collection_uuid(&self, namespace: &str) -> Result<Option<Uuid>> {
+        let (database, collection) = namespace::split(namespace).ok_or(Error::InvalidOperation)?;
+        let mut collections = self.db(database)
+            .list_collections(Some(doc! { "name" => collection }))?;
+
+        match collections.next() {
+            Some(result) => {
+                match optional(result?.get_document("info"))? {
+                    Some(info) => uuid(info, "uuid"),
+                    None => Ok(None),
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn aggregate(&self, namespace: &str, pipeline: Vec<Document>) -> Result<Documents> {
+        let (database, collection) = namespace::split(namespace).ok_or(Error::InvalidOperation)?;
+        let cursor = self.db(database).collection(collection).aggregate(pipeline, None)?;
+
+        Ok(Box::new(cursor.map(|result| result.map_err(Error::from))))
+    }
+
+    fn find(&self,
+            namespace: &str,
+            filter: Document,
+            read_preference: &ReadPreference)
+            -> Result<Documents> {
+        let (database, collection) = namespace::split(namespace).ok_or(Error::InvalidOperation)?;
+
+        let mut opts = FindOptions::new();
+        opts.read_preference = Some(read_preference.clone());
+
+        let cursor = self.db(database).collection(collection).find(Some(filter), Some(opts))?;
+
+        Ok(Box::new(cursor.map(|result| result.map_err(Error::from))))
+    }
+}
+
+/// Returns a tailable cursor over the oplog matching the given filter from the given position.
+fn open_cursor(client: &Client,
+               filter: &Option<Document>,
+               position: Option<Position>)
+               -> Result<Cursor> {
+    let coll = client.db("local").collection("oplog.rs");
+
+    let mut opts = FindOptions::new();
+    opts.cursor_type = CursorType::TailableAwait;
+    opts.no_cursor_timeout = true;
+    opts.oplog_replay = position.is_some();
+
+    Ok(coll.find(query(filter, position), Some(opts))?)
+}
+
+/// Returns the position of the most recent operation in the oplog, if any.
+fn head(client: &Client) -> Result<Option<OpTime>> {
+    let coll = client.db("local").collection("oplog.rs");
+
+    let mut opts = FindOptions::new();
+    opts.sort = Some(doc! { "$natural" => (-1) });
+    opts.projection = Some(doc! { "ts" => 1 });
+
+    match coll.find_one(None, Some(opts))? {
+        Some(document) => Ok(Some(document.get_time_stamp("ts")?.into())),
+        None => Ok(None),
+    }
+}
+
+/// Returns the query for the oplog restricted to entries from the given position, if any.
+fn query(filter: &Option<Document>, position: Option<Position>) -> Option<Document> {
+    let mut clauses = Vec::new();
+
+    if let Some(ref filter) = *filter {
+        clauses.push(filter.clone());
+    }
+
+    if let Some(position) = position {
+        clauses.push(position.to_query());
+    }
+
+    and(clauses)
+}
+
+/// Returns a query matching operations of any of the given kinds.
+///
+/// As both commands and `applyOps` share the same `op`, they are distinguished by the presence of
+/// an `applyOps` field if only one of them is given.
+fn kinds_query(kinds: &[OperationKind]) -> Document {
+    let command = kinds.contains(&OperationKind::Command);
+    let apply_ops = kinds.contains(&OperationKind::ApplyOps);
+
+    let mut ops = Vec::new();
+    for kind in kinds {
+        let op = Bson::String(kind.op().into());
+
+        if !ops.contains(&op) && (command == apply_ops || kind.op() != "c") {
+            ops.push(op);
+        }
+    }
+
+    if command == apply_ops {
+        return doc! { "op" => { "$in" => ops } };
+    }
+
+    let command = doc! { "op" => "c", "o.applyOps" => { "$exists" => apply_ops } };
+
+    if ops.is_empty() {
+        command
+    } else {
+        doc! { "$or" => [{ "op" => { "$in" => ops } }, command] }
+    }
+}
+
+/// Returns the BSON values matching the given namespace patterns.
+fn to_bson(patterns: &[NamespacePattern]) -> Vec<Bson> {
+    patterns.iter().map(NamespacePattern::to_bson).collect()
+}
+
+/// Returns the BSON values matching the given collection UUIDs.
+fn uuids_to_bson(uuids: &[Uuid]) -> Vec<Bson> {
+    uuids.iter().map(uuid_to_bson).collect()
+}
+
+/// Returns the BSON value of the given collection UUID.
+fn uuid_to_bson(uuid: &Uuid) -> Bson {
+    Bson::Binary(BinarySubtype::Uuid, uuid.as_bytes().to_vec())
+}
+
+/// Returns a query matching any of the given clauses, if any.
+fn or(mut clauses: Vec<Document>) -> Option<Document> {
+    match clauses.len() {
+        0 => None,
+        1 => clauses.pop(),
+        _ => {
+            let clauses = clauses.into_iter().map(Bson::Document).collect::<Vec<_>>();
+
+            Some(doc! { "$or" => clauses })
+        }
+    }
+}
+
+/// Returns a query matching all of the given clauses, if any.
+fn and(mut clauses: Vec<Document>) -> Option<Document> {
+    match clauses.len() {
+        0 => None,
+        1 => clauses.pop(),
+        _ => {
+            let clauses = clauses.into_iter().map(Bson::Document).collect::<Vec<_>>();
+
+            Some(doc! { "$and" => clauses })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet, VecDeque};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use bson::{Bson, Document};
+    use bson::spec::BinarySubtype;
+    use mongodb::common::{ReadMode, ReadPreference};
+    use mongodb::connstring::ConnectionString;
+    use mongodb::{self, Client, ThreadedClient};
+    use uuid::Uuid;
+    use {CheckpointPolicy, CheckpointStore, Error, OpTime, Operation, OperationKind, Result,
+         TryTransactions};
+    use super::{kinds_query, lookups, query, Backoff, Checkpointer, Connection, Documents,
+                Entries, InitialSync, OplogBuilder, Position, Query, Source, UpdateLookup,
+                NAMESPACE_FIELD};
+
+    /// A connection opening the given cursors in turn, each returning `None` for a poll without
+    /// new entries and then nothing once exhausted as if killed.
+    ///
+    /// Any scan or lookup returns every given document of its namespace.
+    struct Replay {
+        cursors: Mutex<VecDeque<Vec<Option<Result<Document>>>>>,
+        positions: Arc<Mutex<Vec<Option<Position>>>>,
+        head: Option<OpTime>,
+        heads: Arc<Mutex<Vec<Source>>>,
+        collections: HashMap<String, Vec<Document>>,
+        pipelines: Arc<Mutex<Vec<Vec<Document>>>>,
+        finds: Arc<Mutex<Vec<Document>>>,
+    }
+
+    impl Replay {
+        fn new(cursors: Vec<Vec<Option<Result<Document>>>>) -> Replay {
+            Replay {
+                cursors: Mutex::new(cursors.into_iter().collect()),
+                positions: Arc::new(Mutex::new(Vec::new())),
+                head: None,
+                heads: Arc::new(Mutex::new(Vec::new())),
+                collections: HashMap::new(),
+                pipelines: Arc::new(Mutex::new(Vec::new())),
+                finds: Arc::new(Mutex::new(Vec::new())),
+            }
+        }
+    }
+
+    /// The entries of a cursor opened by a `Replay`.
+    struct Replayed(VecDeque<Option<Result<Document>>>);
+
+    impl Iterator for Replayed {
+        type Item = Result<Document>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.0.pop_front().and_then(|entry| entry)
+        }
+    }
+
+    impl Entries for Replayed {
+        fn is_buffered(&self) -> bool {
+            matches!(self.0.front(), Some(Some(_)))
+        }
+    }
+
+    /// A checkpoint store keeping its position in memory.
+    #[derive(Clone, Default)]
+    struct Checkpoints(Arc<Mutex<Option<OpTime>>>);
+
+    impl CheckpointStore for Checkpoints {
+        fn load(&self) -> Result<Option<OpTime>> {
+            Ok(*self.0.lock().unwrap())
+        }
+
+        fn save(&self, optime: OpTime) -> Result<()> {
+            *self.0.lock().unwrap() = Some(optime);
+
+            Ok(())
+        }
+    }
+
+    impl Connection for Replay {
+        fn open(&self,
+                _query: &Query,
+                position: Option<Position>,
+                _resume_token: Option<&Document>)
+                -> Result<Box<dyn Entries>> {
+            self.positions.lock().unwrap().push(position);
+            let entries = self.cursors.lock().unwrap().pop_front().unwrap_or_default();
+
+            Ok(Box::new(Replayed(entries.into_iter().collect())))
+        }
+
+        fn head(&self, query: &Query) -> Result<Option<OpTime>> {
+            self.heads.lock().unwrap().push(query.source());
+
+            Ok(self.head)
+        }
+
+        fn collection_uuid(&self, _namespace: &str) -> Result<Option<Uuid>> {
+            Ok(Some(Uuid::from_bytes([1; 16])))
+        }
+
+        fn aggregate(&self, namespace: &str, pipeline: Vec<Document>) -> Result<Documents> {
+            self.pipelines.lock().unwrap().push(pipeline);
+            let documents = self.collections.get(namespace).cloned().unwrap_or_default();
+
+            Ok(Box::new(documents.into_iter().map(Ok)))
+        }
+
+        fn find(&self,
+                namespace: &str,
+                filter: Document,
+                _read_preference: &ReadPreference)
+                -> Result<Documents> {
+            self.finds.lock().unwrap().push(filter);
+            let documents = self.collections.get(namespace).cloned().unwrap_or_default();
+
+            Ok(Box::new(documents.into_iter().map(Ok)))
+        }
+    }
+
+    fn client() -> Client {
+        Client::with_config(ConnectionString::new("localhost", 27017), None, None).unwrap()
+    }
+
+    fn noop(increment: i64) -> Document {
+        doc! {
+            "ts" => (Bson::TimeStamp(1479561394 << 32 | increment)),
+            "op" => "n",
+            "ns" => "",
+            "o" => { "msg" => "" }
+        }
+    }
+
+    fn update(increment: i64, id: i32) -> Document {
+        doc! {
+            "ts" => (Bson::TimeStamp(1479561394 << 32 | increment)),
+            "op" => "u",
+            "ns" => "foo.bar",
+            "o" => { "$set" => { "a" => 1 } },
+            "o2" => { "_id" => id }
+        }
+    }
+
+    fn primary() -> ReadPreference {
+        ReadPreference::new(ReadMode::Primary, None)
+    }
+
+    fn apply_ops(increment: i64, id: i32, partial: bool) -> Document {
+        let mut o = doc! {
+            "applyOps" => [{ "op" => "i", "ns" => "foo.bar", "o" => { "_id" => id } }]
+        };
+        if partial {
+            o.insert("partialTxn", true);
+        }
+
+        doc! {
+            "ts" => (Bson::TimeStamp(1479561394 << 32 | increment)),
+            "op" => "c",
+            "ns" => "admin.$cmd",
+            "o" => o,
+            "lsid" => { "id" => 1 },
+            "txnNumber" => 1i64
+        }
+    }
+
+    #[test]
+    fn oplog_resumes_after_errors_and_killed_cursors() {
+        let connection = Replay::new(vec![vec![Some(Ok(noop(1))),
+                                               None,
+                                               Some(Err(Error::Database(
+                                                   mongodb::Error::CursorNotFoundError)))],
+                                          vec![Some(Ok(noop(2)))],
+                                          vec![Some(Ok(noop(3)))]]);
+        let positions = connection.positions.clone();
+        let backoff = Backoff {
+            initial: Duration::from_millis(0),
+            max: Duration::from_millis(0),
+            retries: Some(1),
+        };
+        let mut oplog = OplogBuilder::new(&client())
+            .resume(Some(backoff))
+            .build_with(Box::new(connection))
+            .unwrap();
+
+        let optimes = oplog.try_iter()
+            .take(3)
+            .map(|result| result.unwrap().optime())
+            .collect::<Vec<_>>();
+
+        assert_eq!(optimes,
+                   vec![OpTime::new(1479561394, 1),
+                        OpTime::new(1479561394, 2),
+                        OpTime::new(1479561394, 3)]);
+        assert_eq!(*positions.lock().unwrap(),
+                   vec![None,
+                        Some(Position::After(OpTime::new(1479561394, 1))),
+                        Some(Position::After(OpTime::new(1479561394, 2)))]);
+    }
+
+    #[test]
+    fn try_iter_continues_after_invalid_entries() {
+        let invalid = doc! { "ts" => (Bson::TimeStamp(1479561394 << 32 | 1)), "op" => "x" };
+        let connection = Replay::new(vec![vec![Some(Ok(invalid)), Some(Ok(noop(2)))]]);
+        let mut oplog = OplogBuilder::new(&client()).build_with(Box::new(connection)).unwrap();
+        let mut results = oplog.try_iter();
+
+        match results.next() {
+            Some(Err(Error::UnknownOperation(ref op))) if op == "x" => {}
+            _ => panic!("Expected unknown operation."),
+        }
+        assert_eq!(results.next().unwrap().unwrap().optime(), OpTime::new(1479561394, 2));
+    }
+
+    #[test]
+    fn oplog_returns_killed_cursors_without_backoff() {
+        let connection = Replay::new(vec![vec![Some(Ok(noop(1)))]]);
+        let mut oplog = OplogBuilder::new(&client()).build_with(Box::new(connection)).unwrap();
+
+        assert!(oplog.try_iter().next().unwrap().is_ok());
+        match oplog.try_iter().next() {
+            Some(Err(Error::Database(mongodb::Error::CursorNotFoundError))) => {}
+            _ => panic!("Expected cursor not found."),
+        }
+    }
+
+    #[test]
+    fn update_lookup_checkpoints_returned_operations() {
+        let mut connection = Replay::new(vec![vec![Some(Ok(update(1, 1))),
+                                                   Some(Ok(noop(2))),
+                                                   Some(Ok(noop(3)))]]);
+        connection.collections.insert("foo.bar".into(), vec![doc! { "_id" => 1, "a" => 1 }]);
+        let checkpoints = Checkpoints::default();
+        let policy = CheckpointPolicy {
+            operations: Some(1),
+            interval: None,
+        };
+        let mut oplog = OplogBuilder::new(&client())
+            .checkpoint(checkpoints.clone(), policy)
+            .update_lookup(primary(), 10)
+            .build_with(Box::new(connection))
+            .unwrap();
+
+        match oplog.try_iter().next() {
+            Some(Ok(Operation::Update { full_document, .. })) => {
+                assert_eq!(full_document, Some(doc! { "_id" => 1, "a" => 1 }));
+            }
+            _ => panic!("Expected update."),
+        }
+        assert_eq!(oplog.lookup.as_ref().unwrap().pending.len(), 2);
+        assert_eq!(*checkpoints.0.lock().unwrap(), None);
+
+        assert_eq!(oplog.try_iter().next().unwrap().unwrap().optime(),
+                   OpTime::new(1479561394, 2));
+        assert_eq!(*checkpoints.0.lock().unwrap(), Some(OpTime::new(1479561394, 1)));
+
+        oplog.commit().unwrap();
+
+        assert_eq!(*checkpoints.0.lock().unwrap(), Some(OpTime::new(1479561394, 2)));
+    }
+
+    #[test]
+    fn checkpointer_commits_after_interval_while_idle() {
+        let checkpoints = Checkpoints::default();
+        let interval = Duration::from_secs(60);
+        let policy = CheckpointPolicy {
+            operations: None,
+            interval: Some(interval),
+        };
+        let mut checkpointer = Checkpointer::new(Arc::new(checkpoints.clone()), policy);
+
+        checkpointer.process(OpTime::new(1479561394, 1)).unwrap();
+        checkpointer.process(OpTime::new(1479561394, 1)).unwrap();
+
+        assert_eq!(*checkpoints.0.lock().unwrap(), None);
+
+        checkpointer.last_commit = Instant::now().checked_sub(interval).unwrap();
+        checkpointer.process(OpTime::new(1479561394, 1)).unwrap();
+
+        assert_eq!(*checkpoints.0.lock().unwrap(), Some(OpTime::new(1479561394, 1)));
+    }
+
+    #[test]
+    fn checkpoints_resume_before_pending_transactions() {
+        let checkpoints = Checkpoints::default();
+        let policy = CheckpointPolicy {
+            operations: Some(1),
+            interval: None,
+        };
+        let connection = Replay::new(vec![vec![Some(Ok(noop(1))),
+                                               Some(Ok(apply_ops(2, 1, true))),
+                                               Some(Ok(noop(3)))]]);
+        let mut oplog = OplogBuilder::new(&client())
+            .checkpoint(checkpoints.clone(), policy.clone())
+            .build_with(Box::new(connection))
+            .unwrap();
+        {
+            let mut transactions = TryTransactions::new(oplog.try_iter());
+
+            assert_eq!(transactions.next().unwrap().unwrap().optime(), OpTime::new(1479561394, 1));
+            assert_eq!(transactions.next().unwrap().unwrap().optime(), OpTime::new(1479561394, 3));
+        }
+        oplog.commit().unwrap();
+
+        assert_eq!(*checkpoints.0.lock().unwrap(), Some(OpTime::new(1479561394, 1)));
+
+        let connection = Replay::new(vec![vec![Some(Ok(apply_ops(2, 1, true))),
+                                               Some(Ok(noop(3))),
+                                               Some(Ok(apply_ops(4, 2, false)))]]);
+        let positions = connection.positions.clone();
+        let mut oplog = OplogBuilder::new(&client())
+            .checkpoint(checkpoints.clone(), policy)
+            .build_with(Box::new(connection))
+            .unwrap();
+        let mut transactions = TryTransactions::new(oplog.try_iter());
+
+        assert_eq!(transactions.next().unwrap().unwrap().optime(), OpTime::new(1479561394, 3));
+        match transactions.next() {
+            Some(Ok(Operation::ApplyOps { operations, .. })) => assert_eq!(operations.len(), 2),
+            _ => panic!("Expected committed transaction."),
+        }
+        assert_eq!(*positions.lock().unwrap(),
+                   vec![Some(Position::After(OpTime::new(1479561394, 1)))]);
+    }
+
+    #[test]
+    fn change_stream_checkpoints_wait_for_cluster_time_to_change() {
+        let event = |increment: i64, id: i32| {
+            doc! {
+                "_id" => { "_data" => (id.to_string()) },
+                "operationType" => "insert",
+                "clusterTime" => (Bson::TimeStamp(1479561394 << 32 | increment)),
+                "ns" => { "db" => "foo", "coll" => "bar" },
+                "documentKey" => { "_id" => id },
+                "fullDocument" => { "_id" => id }
+            }
+        };
+        let connection = Replay::new(vec![vec![Some(Ok(event(1, 1))),
+                                               Some(Ok(event(2, 2))),
+                                               Some(Ok(event(2, 3))),
+                                               Some(Ok(event(3, 4)))]]);
+        let checkpoints = Checkpoints::default();
+        let policy = CheckpointPolicy {
+            operations: Some(1),
+            interval: None,
+        };
+        let mut oplog = OplogBuilder::new(&client())
+            .source(Source::ChangeStream)
+            .checkpoint(checkpoints.clone(), policy)
+            .build_with(Box::new(connection))
+            .unwrap();
+
+        for _ in 0..3 {
+            assert!(oplog.try_iter().next().unwrap().is_ok());
+        }
+        oplog.commit().unwrap();
+
+        assert_eq!(*checkpoints.0.lock().unwrap(), Some(OpTime::new(1479561394, 1)));
+
+        assert!(oplog.try_iter().next().unwrap().is_ok());
+        oplog.commit().unwrap();
+
+        assert_eq!(*checkpoints.0.lock().unwrap(), Some(OpTime::new(1479561394, 2)));
+    }
+
+    #[test]
+    fn update_lookup_only_reads_ahead_received_entries() {
+        let connection = Replay::new(vec![vec![Some(Ok(update(1, 1))),
+                                               None,
+                                               Some(Ok(update(2, 2)))]]);
+        let finds = connection.finds.clone();
+        let mut oplog = OplogBuilder::new(&client())
+            .update_lookup(primary(), 10)
+            .build_with(Box::new(connection))
+            .unwrap();
+
+        assert_eq!(oplog.try_iter().next().unwrap().unwrap().optime(),
+                   OpTime::new(1479561394, 1));
+        assert!(oplog.lookup.as_ref().unwrap().pending.is_empty());
+        assert_eq!(*finds.lock().unwrap(), vec![doc! { "_id" => { "$in" => [1] } }]);
+
+        assert_eq!(oplog.try_iter().next().unwrap().unwrap().optime(),
+                   OpTime::new(1479561394, 2));
+        assert_eq!(finds.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn update_lookup_looks_up_updates_in_apply_ops() {
+        let mut connection = Replay::new(Vec::new());
+        connection.collections.insert("foo.bar".into(),
+                                      vec![doc! { "_id" => 1, "a" => 1 },
+                                           doc! { "_id" => 2, "a" => 2 }]);
+        let lookup = UpdateLookup {
+            read_preference: primary(),
+            batch_size: 10,
+            pending: VecDeque::new(),
+        };
+        let mut first = Operation::new(&update(1, 1)).unwrap();
+        let mut apply_ops = Operation::new(&doc! {
+            "ts" => (Bson::TimeStamp(1479561394 << 32 | 2)),
+            "op" => "c",
+            "ns" => "admin.$cmd",
+            "o" => {
+                "applyOps" => [
+                    {
+                        "op" => "u",
+                        "ns" => "foo.bar",
+                        "o" => { "$set" => { "a" => 2 } },
+                        "o2" => { "_id" => 2 }
+                    },
+                    {
+                        "op" => "u",
+                        "ns" => "foo.bar",
+                        "o" => { "$set" => { "a" => 3 } },
+                        "o2" => { "_id" => 3 }
+                    }
+                ]
+            }
+        }).unwrap();
+
+        assert_eq!(lookups(&first), 1);
+        assert_eq!(lookups(&apply_ops), 2);
+
+        lookup.look_up(&connection, &mut [&mut first, &mut apply_ops]).unwrap();
+
+        assert_eq!(*connection.finds.lock().unwrap(),
+                   vec![doc! { "_id" => { "$in" => [1, 2, 3] } }]);
+        match first {
+            Operation::Update { full_document, .. } => {
+                assert_eq!(full_document, Some(doc! { "_id" => 1, "a" => 1 }));
+            }
+            _ => panic!("Expected update."),
+        }
+        match apply_ops {
+            Operation::ApplyOps { operations, .. } => {
+                let documents = operations.into_iter()
+                    .map(|operation| match operation {
+                        Operation::Update { full_document, .. } => full_document,
+                        _ => panic!("Expected update."),
+                    })
+                    .collect::<Vec<_>>();
+
+                assert_eq!(documents, vec![Some(doc! { "_id" => 2, "a" => 2 }), None]);
+            }
+            _ => panic!("Expected applyOps."),
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_delay_up_to_max() {
+        let backoff = Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(5),
+            retries: None,
+        };
+
+        assert_eq!(backoff.delay(0), Some(Duration::from_secs(1)));
+        assert_eq!(backoff.delay(1), Some(Duration::from_secs(2)));
+        assert_eq!(backoff.delay(2), Some(Duration::from_secs(4)));
+        assert_eq!(backoff.delay(3), Some(Duration::from_secs(5)));
+        assert_eq!(backoff.delay(100), Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn backoff_stops_after_retries() {
+        let backoff = Backoff { retries: Some(2), ..Backoff::default() };
+
+        assert!(backoff.delay(1).is_some());
+        assert_eq!(backoff.delay(2), None);
+    }
+
+    #[test]
+    fn query_without_position_is_filter() {
+        assert_eq!(query(&None, None), None);
+        assert_eq!(query(&Some(doc! { "op" => "i" }), None), Some(doc! { "op" => "i" }));
+    }
+
+    #[test]
+    fn query_resumes_after_timestamp() {
+        assert_eq!(query(&None, Some(Position::After(OpTime::new(1, 0)))),
+                   Some(doc! { "ts" => { "$gt" => (Bson::TimeStamp(1 << 32)) } }));
+        assert_eq!(query(&Some(doc! { "op" => "i" }), Some(Position::After(OpTime::new(1, 0)))),
+                   Some(doc! {
+                       "$and" => [
+                           { "op" => "i" },
+                           { "ts" => { "$gt" => (Bson::TimeStamp(1 << 32)) } }
+                       ]
+                   }));
+    }
+
+    #[test]
+    fn builder_filters_namespaces() {
+        let client = client();
+        let mut builder = OplogBuilder::new(&client);
+        builder.filter(Some(doc! { "op" => "i" }))
+            .include_namespace("foo.bar")
+            .include_database("baz")
+            .exclude_namespace("baz.*");
+
+        assert_eq!(builder.query_filter(),
+                   Some(doc! {
+                       "$and" => [
+                           { "op" => "i" },
+                           {
+                               "$or" => [
+                                   {
+                                       "ns" => {
+                                           "$in" => [
+                                               "foo.bar",
+                                               (Bson::RegExp("^baz\\.".into(), "".into()))
+                                           ]
+                                       }
+                                   },
+                                   {
+                                       "o.applyOps.ns" => {
+                                           "$in" => [
+                                               "foo.bar",
+                                               (Bson::RegExp("^baz\\.".into(), "".into()))
+                                           ]
+                                       }
+                                   }
+                               ]
+                           },
+                           {
+                               "ns" => {
+                                   "$nin" => [(Bson::RegExp("^baz\\..*$".into(), "".into()))]
+                               }
+                           },
+                           {
+                               "$or" => [
+                                   { "o.applyOps" => { "$exists" => false } },
+                                   {
+                                       "o.applyOps" => {
+                                           "$elemMatch" => {
+                                               "ns" => {
+                                                   "$nin" => [
+                                                       (Bson::RegExp("^baz\\..*$".into(),
+                                                                     "".into()))
+                                                   ]
+                                               }
+                                           }
+                                       }
+                                   }
+                               ]
+                           }
+                       ]
+                   }));
+    }
+
+    #[test]
+    fn builder_filters_uuids() {
+        let client = client();
+        let mut builder = OplogBuilder::new(&client);
+        builder.include_uuid(Uuid::from_bytes([1; 16]));
+        let uuid = Bson::Binary(BinarySubtype::Uuid, vec![1; 16]);
+
+        assert_eq!(builder.query_filter(),
+                   Some(doc! {
+                       "$or" => [
+                           { "ui" => { "$in" => [(uuid.clone())] } },
+                           { "o.applyOps.ui" => { "$in" => [uuid] } }
+                       ]
+                   }));
+    }
+
+    #[test]
+    fn builder_filters_change_streams() {
+        let client = client();
+        let mut builder = OplogBuilder::new(&client);
+        builder.filter(Some(doc! { "fullDocument.foo" => "bar" }))
+            .include_database("foo")
+            .exclude_namespace("foo.baz")
+            .kinds(&[OperationKind::Update, OperationKind::Noop]);
+
+        assert_eq!(builder.pipeline(),
+                   vec![doc! {
+                            "$addFields" => {
+                                NAMESPACE_FIELD => {
+                                    "$concat" => [
+                                        "$ns.db",
+                                        ".",
+                                        { "$ifNull" => ["$ns.coll", { "$literal" => "$cmd" }] }
+                                    ]
+                                }
+                            }
+                        },
+                        doc! {
+                            "$match" => {
+                                "$and" => [
+                                    {
+                                        NAMESPACE_FIELD => {
+                                            "$in" => [(Bson::RegExp("^foo\\.".into(), "".into()))]
+                                        }
+                                    },
+                                    { NAMESPACE_FIELD => { "$nin" => ["foo.baz"] } }
+                                ]
+                            }
+                        },
+                        doc! { "$project" => { NAMESPACE_FIELD => 0 } },
+                        doc! {
+                            "$match" => { "operationType" => { "$in" => ["update", "replace"] } }
+                        },
+                        doc! { "$match" => { "fullDocument.foo" => "bar" } }]);
+    }
+
+    #[test]
+    fn position_converts_to_change_stream_optime() {
+        assert_eq!(Position::At(OpTime::new(1, 2)).to_optime(), OpTime::new(1, 2));
+        assert_eq!(Position::After(OpTime::new(1, 2)).to_optime(), OpTime::new(1, 3));
+        assert_eq!(Position::After(OpTime::new(1, u32::MAX)).to_optime(), OpTime::new(2, 0));
+    }
+
+    #[test]
+    fn initial_sync_scans_namespaces_with_filters() {
+        let mut connection = Replay::new(vec![vec![Some(Ok(noop(1)))]]);
+        connection.head = Some(OpTime::new(1479561394, 0));
+        connection.collections.insert("foo.bar".into(), vec![doc! { "_id" => 1 }]);
+        let positions = connection.positions.clone();
+        let pipelines = connection.pipelines.clone();
+        let mut oplog = OplogBuilder::new(&client())
+            .filter(Some(doc! { "o.a" => 1 }))
+            .initial_sync("foo.bar")
+            .build_with(Box::new(connection))
+            .unwrap();
+        let uuid = Uuid::from_bytes([1; 16]);
+
+        assert_eq!(oplog.try_iter().next().unwrap().unwrap(),
+                   Operation::Insert {
+                       id: None,
+                       optime: OpTime::new(1479561394, 0),
+                       timestamp: OpTime::new(1479561394, 0).to_datetime(),
+                       term: None,
+                       wall: None,
+                       version: None,
+                       transaction: None,
+                       namespace: "foo.bar".into(),
+                       uuid: Some(uuid),
+                       document: doc! { "_id" => 1 },
+                   });
+        assert_eq!(oplog.try_iter().next().unwrap().unwrap().optime(),
+                   OpTime::new(1479561394, 1));
+        assert_eq!(*positions.lock().unwrap(),
+                   vec![Some(Position::After(OpTime::new(1479561394, 0)))]);
+        assert_eq!(*pipelines.lock().unwrap(),
+                   vec![vec![doc! {
+                                 "$replaceRoot" => {
+                                     "newRoot" => {
+                                         "ts" => {
+                                             "$literal" => (Bson::TimeStamp(1479561394 << 32))
+                                         },
+                                         "op" => { "$literal" => "i" },
+                                         "ns" => { "$literal" => "foo.bar" },
+                                         "o" => "$$ROOT",
+                                         "ui" => {
+                                             "$literal" => (Bson::Binary(BinarySubtype::Uuid,
+                                                                         vec![1; 16]))
+                                         }
+                                     }
+                                 }
+                             },
+                             doc! { "$match" => { "o.a" => 1 } },
+                             doc! { "$replaceRoot" => { "newRoot" => "$o" } }]]);
+    }
+
+    #[test]
+    fn initial_sync_skips_inserts_of_scanned_documents_in_apply_ops() {
+        let mut connection = Replay::new(vec![vec![Some(Ok(doc! {
+            "ts" => (Bson::TimeStamp(1479561394 << 32 | 1)),
+            "op" => "c",
+            "ns" => "admin.$cmd",
+            "o" => {
+                "applyOps" => [
+                    { "op" => "i", "ns" => "foo.bar", "o" => { "_id" => 1 } },
+                    { "op" => "i", "ns" => "foo.bar", "o" => { "_id" => 2 } }
+                ]
+            }
+        }))]]);
+        connection.head = Some(OpTime::new(1479561394, 2));
+        connection.collections.insert("foo.bar".into(), vec![doc! { "_id" => 1 }]);
+        let mut oplog = OplogBuilder::new(&client())
+            .initial_sync("foo.bar")
+            .build_with(Box::new(connection))
+            .unwrap();
+
+        assert!(oplog.try_iter().next().unwrap().is_ok());
+        match oplog.try_iter().next() {
+            Some(Ok(Operation::ApplyOps { operations, .. })) => {
+                assert_eq!(operations.len(), 1);
+                match operations[0] {
+                    Operation::Insert { ref document, .. } => {
+                        assert_eq!(*document, doc! { "_id" => 2 });
+                    }
+                    _ => panic!("Expected insert."),
+                }
+            }
+            _ => panic!("Expected applyOps."),
+        }
+    }
+
+    #[test]
+    fn initial_sync_takes_cluster_time_of_change_streams() {
+        let mut connection = Replay::new(Vec::new());
+        connection.head = Some(OpTime::new(1479561394, 0));
+        let heads = connection.heads.clone();
+        let mut oplog = OplogBuilder::new(&client())
+            .source(Source::ChangeStream)
+            .initial_sync("foo.bar")
+            .build_with(Box::new(connection))
+            .unwrap();
+
+        assert!(oplog.poll_operation().is_none());
+        assert_eq!(*heads.lock().unwrap(), vec![Source::ChangeStream, Source::ChangeStream]);
+    }
+
+    #[test]
+    fn initial_sync_filters_change_events() {
+        let query = Query::ChangeStream(vec![doc! { "$match" => { "fullDocument.a" => 1 } }],
+                                        false);
+
+        assert_eq!(query.scan_pipeline("foo.bar", None, OpTime::new(1479561394, 0)),
+                   vec![doc! {
+                            "$replaceRoot" => {
+                                "newRoot" => {
+                                    "operationType" => { "$literal" => "insert" },
+                                    "clusterTime" => {
+                                        "$literal" => (Bson::TimeStamp(1479561394 << 32))
+                                    },
+                                    "ns" => {
+                                        "db" => { "$literal" => "foo" },
+                                        "coll" => { "$literal" => "bar" }
+                                    },
+                                    "documentKey" => { "_id" => "$_id" },
+                                    "fullDocument" => "$$ROOT"
+                                }
+                            }
+                        },
+                        doc! { "$match" => { "fullDocument.a" => 1 } },
+                        doc! { "$replaceRoot" => { "newRoot" => "$fullDocument" } }]);
+    }
+
+    #[test]
+    fn initial_sync_rejects_invalid_namespaces() {
+        for namespace in &["foo", ".bar", "foo."] {
+            let connection = Replay::new(Vec::new());
+            let result = OplogBuilder::new(&client())
+                .initial_sync(namespace)
+                .build_with(Box::new(connection));
+
+            match result {
+                Err(Error::InvalidOperation) => {}
+                _ => panic!("Expected invalid operation."),
+            }
+        }
+    }
+
+    #[test]
+    fn initial_sync_skips_inserts_of_scanned_documents() {
+        let mut seen = HashSet::new();
+        seen.insert(("foo.bar".to_owned(), Bson::I32(1).to_string()));
+        let mut sync = InitialSync {
+            namespaces: VecDeque::new(),
+            scan: None,
+            optime: OpTime::new(1479561394, 0),
+            end: Some(OpTime::new(1479561395, 0)),
+            seen,
+        };
+        let insert = Operation::new(&doc! {
+            "ts" => (Bson::TimeStamp(1479561394 << 32 | 1)),
+            "op" => "i",
+            "ns" => "foo.bar",
+            "o" => { "_id" => 1 }
+        }).unwrap();
+        let delete = Operation::new(&doc! {
+            "ts" => (Bson::TimeStamp(1479561394 << 32 | 2)),
+            "op" => "d",
+            "ns" => "foo.bar",
+            "o" => { "_id" => 1 }
+        }).unwrap();
+        let other = Operation::new(&doc! {
+            "ts" => (Bson::TimeStamp(1479561394 << 32 | 3)),
+            "op" => "i",
+            "ns" => "foo.baz",
+            "o" => { "_id" => 1 }
+        }).unwrap();
+
+        assert!(sync.is_duplicate(&insert));
+        assert!(!sync.is_duplicate(&other));
+        assert!(!sync.is_duplicate(&delete));
+        assert!(!sync.is_duplicate(&insert));
+    }
+
+    #[test]
+    fn kinds_query_matches_ops() {
+        assert_eq!(kinds_query(&[OperationKind::Insert, OperationKind::Update]),
+                   doc! { "op" => { "$in" => ["i", "u"] } });
+        assert_eq!(kinds_query(&[OperationKind::Command, OperationKind::ApplyOps]),
+                   doc! { "op" => { "$in" => ["c"] } });
+    }
+
+    #[test]
+    fn kinds_query_distinguishes_commands_from_apply_ops() {
+        assert_eq!(kinds_query(&[OperationKind::Insert, OperationKind::ApplyOps]),
+                   doc! {
+                       "$or" => [
+                           { "op" => { "$in" => ["i"] } },
+                           { "op" => "c", "o.applyOps" => { "$exists" => true } }
+                       ]
+                   });
+        assert_eq!(kinds_query(&[OperationKind::Command]),
+                   doc! { "op" => "c", "o.applyOps" => { "$exists" => false } });
+    }
+
+    #[test]
+    fn query_starts_at_timestamp() {
+        assert_eq!(query(&None, Some(Position::At(OpTime::new(1, 0)))),
+                   Some(doc! { "ts" => { "$gte" => (Bson::TimeStamp(1 << 32)) } }));
+    }
 }
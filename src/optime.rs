@@ -0,0 +1,114 @@
+//! The optime module is responsible for representing the exact position of an operation in the
+//! oplog as recorded by its BSON timestamp.
+//!
+//! A BSON timestamp is made up of the number of seconds since the Unix epoch and an increment
+//! which orders operations within the same second. As the increment is an ordinal rather than a
+//! fraction of a second, an `OpTime` is only approximately a point in time.
+
+use std::fmt;
+
+use bson::Bson;
+use chrono::{DateTime, TimeZone, Utc};
+
+/// The position of an operation in the oplog.
+///
+/// `OpTime`s are totally ordered in the same way as the oplog itself: first by seconds and then by
+/// increment.
+///
+/// # Example
+///
+/// ```
+/// # extern crate bson;
+/// # extern crate oplog;
+/// use bson::Bson;
+/// use oplog::OpTime;
+///
+/// # fn main() {
+/// let optime = OpTime::new(1479561394, 1);
+///
+/// assert!(optime < OpTime::new(1479561394, 2));
+/// assert_eq!(Bson::from(optime), Bson::TimeStamp((1479561394 << 32) + 1));
+/// # }
+/// ```
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct OpTime {
+    /// The number of seconds since the Unix epoch.
+    pub seconds: u32,
+    /// An ordinal distinguishing operations within the same second.
+    pub increment: u32,
+}
+
+impl OpTime {
+    /// Returns a new `OpTime` for the given seconds and increment.
+    pub fn new(seconds: u32, increment: u32) -> OpTime {
+        OpTime { seconds, increment }
+    }
+
+    /// Returns the time of this `OpTime` as a UTC `DateTime`, discarding the increment.
+    pub fn to_datetime(&self) -> DateTime<Utc> {
+        Utc.timestamp_opt(i64::from(self.seconds), 0).unwrap()
+    }
+}
+
+impl From<i64> for OpTime {
+    /// Converts the value of a `Bson::TimeStamp` into an `OpTime`.
+    fn from(timestamp: i64) -> OpTime {
+        OpTime {
+            seconds: (timestamp >> 32) as u32,
+            increment: timestamp as u32,
+        }
+    }
+}
+
+impl From<OpTime> for i64 {
+    /// Converts an `OpTime` into the value of a `Bson::TimeStamp`.
+    fn from(optime: OpTime) -> i64 {
+        ((u64::from(optime.seconds) << 32) | u64::from(optime.increment)) as i64
+    }
+}
+
+impl From<OpTime> for Bson {
+    fn from(optime: OpTime) -> Bson {
+        Bson::TimeStamp(optime.into())
+    }
+}
+
+impl fmt::Display for OpTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Timestamp({}, {})", self.seconds, self.increment)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::Bson;
+    use chrono::{TimeZone, Utc};
+    use super::OpTime;
+
+    #[test]
+    fn optime_converts_bson_timestamps() {
+        let optime = OpTime::from((1479561394 << 32) + 3);
+
+        assert_eq!(optime, OpTime::new(1479561394, 3));
+        assert_eq!(Bson::from(optime), Bson::TimeStamp((1479561394 << 32) + 3));
+    }
+
+    #[test]
+    fn optime_round_trips_large_values() {
+        let optime = OpTime::new(u32::MAX, u32::MAX);
+
+        assert_eq!(OpTime::from(i64::from(optime)), optime);
+    }
+
+    #[test]
+    fn optime_orders_by_seconds_then_increment() {
+        assert!(OpTime::new(1, 2) < OpTime::new(2, 1));
+        assert!(OpTime::new(1, 1) < OpTime::new(1, 2));
+    }
+
+    #[test]
+    fn optime_ignores_increment_in_datetime() {
+        assert_eq!(OpTime::new(1479561394, 1500).to_datetime(),
+                   Utc.timestamp_opt(1479561394, 0).unwrap());
+    }
+}
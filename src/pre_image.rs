@@ -0,0 +1,469 @@
+//! The pre-image module is responsible for attaching the previous version of a document to the
+//! updates and deletes that change it.
+//!
+//! Pre-images are taken from the no-ops that MongoDB 4.4 and 5.0 write before each update and
+//! delete of a collection with `recordPreImages` enabled, otherwise from the inserts and updates
+//! observed earlier in the oplog. The `config.system.preimages` collection written by MongoDB 6.0
+//! and later is not read, as those servers no longer accept the legacy wire protocol of the
+//! mongodb driver.
+
+use std::collections::{HashMap, HashSet};
+
+use bson::{Bson, Document};
+
+use update;
+use {CommandKind, DocumentStore, MemoryDocumentStore, OpTime, Operation, Result,
+     UpdateDescription};
+
+/// An iterator adapter setting the `pre_image` of each update and delete.
+///
+/// An update or delete with a `pre_image_optime` takes its pre-image from the no-op at that
+/// position, provided the no-op was read just before it (so no-ops must not be filtered out of
+/// the oplog). Otherwise, every document inserted or updated is kept in a `DocumentStore` so that
+/// the version before a later update or delete is known. Documents changed before the first
+/// operation read (e.g. before the oplog's starting position) have no pre-image.
+///
+/// Note that the store keeps the latest version of every document observed until it is deleted
+/// or its collection dropped, so it grows with the data being followed. Restrict the oplog to the
+/// namespaces whose pre-images are needed, or provide a store that keeps documents out of memory.
+///
+/// As each operation is only yielded once its store has been updated, a store error is yielded
+/// in place of the operation. Use `TryPreImages` to set the pre-images of the results of
+/// `Oplog::try_iter` instead.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # extern crate mongodb;
+/// # extern crate oplog;
+/// use mongodb::{Client, ThreadedClient};
+/// use oplog::{Operation, OplogBuilder, PreImages};
+///
+/// # fn main() {
+/// let client = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+///
+/// if let Ok(oplog) = OplogBuilder::new(&client).include_database("app").build() {
+///     for result in PreImages::new(oplog) {
+///         if let Ok(Operation::Delete { pre_image: Some(document), .. }) = result {
+///             // Do something with the deleted document...
+///         }
+///     }
+/// }
+/// # }
+/// ```
+pub struct PreImages<I, S: DocumentStore = MemoryDocumentStore> {
+    operations: I,
+    store: S,
+    /// The namespaces of every document kept in the store.
+    namespaces: HashSet<String>,
+    /// The objects of the no-ops read since the last other operation, by position.
+    recorded: HashMap<OpTime, Document>,
+}
+
+impl<I> PreImages<I, MemoryDocumentStore>
+    where I: Iterator<Item = Operation>
+{
+    /// Returns a new adapter over the given operations keeping documents in memory.
+    pub fn new(operations: I) -> PreImages<I, MemoryDocumentStore> {
+        PreImages::with_store(operations, MemoryDocumentStore::new())
+    }
+}
+
+impl<I, S> PreImages<I, S>
+    where I: Iterator<Item = Operation>,
+          S: DocumentStore
+{
+    /// Returns a new adapter over the given operations keeping documents in the given store.
+    pub fn with_store(operations: I, store: S) -> PreImages<I, S> {
+        PreImages {
+            operations,
+            store,
+            namespaces: HashSet::new(),
+            recorded: HashMap::new(),
+        }
+    }
+}
+
+impl<I, S: DocumentStore> PreImages<I, S> {
+    /// Returns the store of documents.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Sets the pre-image of the given operation and updates the store with its changes.
+    ///
+    /// No-ops are kept as possible pre-images until another operation is read.
+    fn process(&mut self, operation: &mut Operation) -> Result<()> {
+        if let Operation::Noop { optime, ref object, .. } = *operation {
+            self.recorded.insert(optime, object.clone());
+
+            return Ok(());
+        }
+
+        let result = self.attach(operation);
+        self.recorded.clear();
+
+        result
+    }
+
+    /// Returns the pre-image of the document with the given `_id`, preferring one recorded by
+    /// the server at the given position.
+    fn pre_image(&mut self,
+                 namespace: &str,
+                 id: &Bson,
+                 optime: Option<OpTime>)
+                 -> Result<Option<Document>> {
+        match optime.and_then(|optime| self.recorded.remove(&optime)) {
+            Some(document) => Ok(Some(document)),
+            None => self.store.get(namespace, id),
+        }
+    }
+
+    /// Sets the pre-image of the given update, delete or operations of an `applyOps` command and
+    /// updates the store with their changes.
+    fn attach(&mut self, operation: &mut Operation) -> Result<()> {
+        if let Some(kind) = operation.command_kind() {
+            return self.clear(kind);
+        }
+
+        match *operation {
+            Operation::Insert { ref namespace, ref document, .. }
+                if document.contains_key("_id") => {
+                self.namespaces.insert(namespace.clone());
+                self.store.put(namespace, document.clone())?;
+            }
+            Operation::Update { ref namespace,
+                                ref query,
+                                ref update,
+                                ref full_document,
+                                pre_image_optime,
+                                ref mut pre_image,
+                                .. } => {
+                let id = match query.get("_id") {
+                    Some(id) => id,
+                    None => return Ok(()),
+                };
+
+                if pre_image.is_none() {
+                    *pre_image = self.pre_image(namespace, id, pre_image_optime)?;
+                }
+
+                let post_image = match (full_document.as_ref(), pre_image.as_ref()) {
+                    (Some(document), _) => Some(document.clone()),
+                    (None, Some(document)) => update::apply(document, update).ok(),
+                    (None, None) => {
+                        match UpdateDescription::new(update) {
+                            Ok(replacement @ UpdateDescription::Replacement(_)) => {
+                                replacement.apply_to(&doc! { "_id" => (id.clone()) }).ok()
+                            }
+                            _ => None,
+                        }
+                    }
+                };
+
+                match post_image {
+                    Some(document) => {
+                        self.namespaces.insert(namespace.clone());
+                        self.store.put(namespace, document)?;
+                    }
+                    None => self.store.remove(namespace, id)?,
+                }
+            }
+            Operation::Delete { ref namespace,
+                                ref query,
+                                pre_image_optime,
+                                ref mut pre_image,
+                                .. } => {
+                if let Some(id) = query.get("_id") {
+                    if pre_image.is_none() {
+                        *pre_image = self.pre_image(namespace, id, pre_image_optime)?;
+                    }
+
+                    self.store.remove(namespace, id)?;
+                }
+            }
+            Operation::ApplyOps { ref mut operations, .. } => {
+                for operation in operations {
+                    self.attach(operation)?;
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Removes the documents of any collections dropped by the given command, moving those of a
+    /// renamed collection to its new namespace.
+    fn clear(&mut self, kind: CommandKind) -> Result<()> {
+        let cleared = match kind {
+            CommandKind::Drop { namespace } => vec![namespace],
+            CommandKind::RenameCollection { from, to, .. } => {
+                if self.namespaces.remove(&from) {
+                    self.namespaces.insert(to.clone());
+                } else {
+                    self.namespaces.remove(&to);
+                }
+
+                return self.store.rename(&from, &to);
+            }
+            CommandKind::DropDatabase { database } => {
+                let prefix = format!("{}.", database);
+
+                self.namespaces
+                    .iter()
+                    .filter(|namespace| namespace.starts_with(&prefix))
+                    .cloned()
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+
+        for namespace in cleared {
+            self.namespaces.remove(&namespace);
+            self.store.clear(&namespace)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<I, S> Iterator for PreImages<I, S>
+    where I: Iterator<Item = Operation>,
+          S: DocumentStore
+{
+    type Item = Result<Operation>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut operation = self.operations.next()?;
+
+        Some(self.process(&mut operation).map(|_| operation))
+    }
+}
+
+/// An iterator adapter setting the `pre_image` of each update and delete from the result of
+/// reading each entry, e.g. as returned by `Oplog::try_iter`.
+///
+/// Pre-images are set as by `PreImages` and errors reading entries are yielded as they are read.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # extern crate mongodb;
+/// # extern crate oplog;
+/// use mongodb::{Client, ThreadedClient};
+/// use oplog::{Operation, Oplog, TryPreImages, TryTransactions};
+///
+/// # fn main() {
+/// let client = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+///
+/// if let Ok(mut oplog) = Oplog::new(&client) {
+///     for result in TryPreImages::new(TryTransactions::new(oplog.try_iter())) {
+///         match result {
+///             Ok(Operation::Delete { pre_image: Some(document), .. }) => {
+///                 // Do something with the deleted document...
+///             }
+///             Ok(_) => {}
+///             Err(err) => eprintln!("Error reading oplog: {}", err),
+///         }
+///     }
+/// }
+/// # }
+/// ```
+pub struct TryPreImages<I, S: DocumentStore = MemoryDocumentStore> {
+    pre_images: PreImages<I, S>,
+}
+
+impl<I> TryPreImages<I, MemoryDocumentStore>
+    where I: Iterator<Item = Result<Operation>>
+{
+    /// Returns a new adapter over the given results keeping documents in memory.
+    pub fn new(results: I) -> TryPreImages<I, MemoryDocumentStore> {
+        TryPreImages::with_store(results, MemoryDocumentStore::new())
+    }
+}
+
+impl<I, S> TryPreImages<I, S>
+    where I: Iterator<Item = Result<Operation>>,
+          S: DocumentStore
+{
+    /// Returns a new adapter over the given results keeping documents in the given store.
+    pub fn with_store(results: I, store: S) -> TryPreImages<I, S> {
+        TryPreImages {
+            pre_images: PreImages {
+                operations: results,
+                store,
+                namespaces: HashSet::new(),
+                recorded: HashMap::new(),
+            },
+        }
+    }
+
+    /// Returns the store of documents.
+    pub fn store(&self) -> &S {
+        self.pre_images.store()
+    }
+}
+
+impl<I, S> Iterator for TryPreImages<I, S>
+    where I: Iterator<Item = Result<Operation>>,
+          S: DocumentStore
+{
+    type Item = Result<Operation>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut operation = match self.pre_images.operations.next()? {
+            Ok(operation) => operation,
+            Err(err) => return Some(Err(err)),
+        };
+
+        Some(self.pre_images.process(&mut operation).map(|_| operation))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::{Bson, Document};
+    use {Error, Operation};
+    use super::{PreImages, TryPreImages};
+
+    fn insert(ns: &str, document: Document) -> Operation {
+        Operation::new(&doc! {
+            "ts" => (Bson::TimeStamp(1479561394 << 32)),
+            "op" => "i",
+            "ns" => ns,
+            "o" => document
+        }).unwrap()
+    }
+
+    fn update(ns: &str, id: i32, update: Document) -> Operation {
+        Operation::new(&doc! {
+            "ts" => (Bson::TimeStamp(1479561394 << 32)),
+            "op" => "u",
+            "ns" => ns,
+            "o" => update,
+            "o2" => { "_id" => id }
+        }).unwrap()
+    }
+
+    fn delete(ns: &str, id: i32) -> Operation {
+        Operation::new(&doc! {
+            "ts" => (Bson::TimeStamp(1479561394 << 32)),
+            "op" => "d",
+            "ns" => ns,
+            "o" => { "_id" => id }
+        }).unwrap()
+    }
+
+    fn command(command: Document) -> Operation {
+        Operation::new(&doc! {
+            "ts" => (Bson::TimeStamp(1479561394 << 32)),
+            "op" => "c",
+            "ns" => "foo.$cmd",
+            "o" => command
+        }).unwrap()
+    }
+
+    #[test]
+    fn pre_images_attaches_previous_versions() {
+        let operations = vec![insert("foo.bar", doc! { "_id" => 1, "a" => 1 }),
+                              update("foo.bar", 1, doc! { "$set" => { "a" => 2 } }),
+                              delete("foo.bar", 1),
+                              delete("foo.bar", 2)];
+        let pre_images = PreImages::new(operations.into_iter())
+            .map(|result| match result.unwrap() {
+                Operation::Update { pre_image, .. } |
+                Operation::Delete { pre_image, .. } => pre_image,
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(pre_images,
+                   vec![None,
+                        Some(doc! { "_id" => 1, "a" => 1 }),
+                        Some(doc! { "_id" => 1, "a" => 2 }),
+                        None]);
+    }
+
+    #[test]
+    fn pre_images_forgets_dropped_collections() {
+        let operations = vec![insert("foo.bar", doc! { "_id" => 1 }),
+                              command(doc! { "dropDatabase" => 1 }),
+                              delete("foo.bar", 1)];
+
+        match PreImages::new(operations.into_iter()).last() {
+            Some(Ok(Operation::Delete { pre_image, .. })) => assert_eq!(pre_image, None),
+            _ => panic!("Expected delete."),
+        }
+    }
+
+    #[test]
+    fn pre_images_follows_renamed_collections() {
+        let operations = vec![insert("foo.bar", doc! { "_id" => 1 }),
+                              insert("foo.baz", doc! { "_id" => 2 }),
+                              command(doc! {
+                                  "renameCollection" => "foo.bar",
+                                  "to" => "foo.baz",
+                                  "dropTarget" => true
+                              }),
+                              delete("foo.bar", 1),
+                              delete("foo.baz", 2),
+                              delete("foo.baz", 1)];
+        let pre_images = PreImages::new(operations.into_iter())
+            .skip(3)
+            .map(|result| match result.unwrap() {
+                Operation::Delete { pre_image, .. } => pre_image,
+                _ => panic!("Expected delete."),
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(pre_images, vec![None, None, Some(doc! { "_id" => 1 })]);
+    }
+
+    #[test]
+    fn pre_images_reads_pre_images_recorded_by_the_server() {
+        let operations = vec![Operation::new(&doc! {
+                                  "ts" => (Bson::TimeStamp(1479561394 << 32 | 1)),
+                                  "op" => "n",
+                                  "ns" => "foo.bar",
+                                  "o" => { "_id" => 1, "a" => 1 }
+                              }).unwrap(),
+                              Operation::new(&doc! {
+                                  "ts" => (Bson::TimeStamp(1479561394 << 32 | 2)),
+                                  "op" => "d",
+                                  "ns" => "foo.bar",
+                                  "o" => { "_id" => 1 },
+                                  "preImageOpTime" => {
+                                      "ts" => (Bson::TimeStamp(1479561394 << 32 | 1)),
+                                      "t" => 1i64
+                                  }
+                              }).unwrap()];
+
+        match PreImages::new(operations.into_iter()).last() {
+            Some(Ok(Operation::Delete { pre_image, .. })) => {
+                assert_eq!(pre_image, Some(doc! { "_id" => 1, "a" => 1 }));
+            }
+            _ => panic!("Expected delete."),
+        }
+    }
+
+    #[test]
+    fn try_pre_images_yields_errors() {
+        let results = vec![Ok(insert("foo.bar", doc! { "_id" => 1 })),
+                           Err(Error::InvalidOperation),
+                           Ok(delete("foo.bar", 1))];
+        let mut pre_images = TryPreImages::new(results.into_iter());
+
+        assert!(pre_images.next().unwrap().is_ok());
+        match pre_images.next() {
+            Some(Err(Error::InvalidOperation)) => {}
+            _ => panic!("Expected invalid operation."),
+        }
+        match pre_images.next() {
+            Some(Ok(Operation::Delete { pre_image, .. })) => {
+                assert_eq!(pre_image, Some(doc! { "_id" => 1 }));
+            }
+            _ => panic!("Expected delete."),
+        }
+    }
+}
@@ -0,0 +1,115 @@
+//! The raw module is responsible for keeping the original BSON document read from an oplog or
+//! change stream so that it can be inspected, archived or forwarded as-is, only decoding it into
+//! an `Operation` when asked.
+
+use bson::Document;
+
+use change_stream;
+use {OpTime, Operation, Result, Source};
+
+/// An entry exactly as read from an oplog or change stream.
+///
+/// Unlike an `Operation`, this keeps every field of the original document, including any that
+/// this library does not understand. The entry is only decoded when one of its accessors is
+/// called.
+///
+/// This is yielded by `Oplog::raw_iter`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RawOperation {
+    document: Document,
+    source: Source,
+}
+
+impl RawOperation {
+    /// Returns a new raw entry for the given document read from the given source.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[macro_use]
+    /// # extern crate bson;
+    /// # extern crate oplog;
+    /// # use bson::Bson;
+    /// use oplog::{RawOperation, Source};
+    ///
+    /// # fn main() {
+    /// let document = doc! {
+    ///     "ts" => (Bson::TimeStamp(1479561394 << 32)),
+    ///     "h" => (-1742072865587022793i64),
+    ///     "v" => 2,
+    ///     "op" => "i",
+    ///     "ns" => "foo.bar",
+    ///     "o" => {
+    ///         "foo" => "bar"
+    ///     },
+    ///     "fromMigrate" => true
+    /// };
+    /// let raw = RawOperation::new(document, Source::Oplog);
+    ///
+    /// assert_eq!(raw.document().get_bool("fromMigrate"), Ok(true));
+    /// assert!(raw.operation().is_ok());
+    /// # }
+    /// ```
+    pub fn new(document: Document, source: Source) -> RawOperation {
+        RawOperation { document, source }
+    }
+
+    /// Returns the original document.
+    pub fn document(&self) -> &Document {
+        &self.document
+    }
+
+    /// Returns the original document, consuming the entry.
+    pub fn into_document(self) -> Document {
+        self.document
+    }
+
+    /// Returns the source the entry was read from.
+    pub fn source(&self) -> Source {
+        self.source
+    }
+
+    /// Returns the position of the entry without decoding the rest of it.
+    pub fn optime(&self) -> Result<OpTime> {
+        let key = match self.source {
+            Source::Oplog => "ts",
+            Source::ChangeStream => "clusterTime",
+        };
+
+        Ok(self.document.get_time_stamp(key)?.into())
+    }
+
+    /// Decodes the entry into an `Operation`.
+    pub fn operation(&self) -> Result<Operation> {
+        match self.source {
+            Source::Oplog => Operation::new(&self.document),
+            Source::ChangeStream => change_stream::operation_from_event(&self.document),
+        }
+    }
+}
+
+impl From<RawOperation> for Document {
+    fn from(raw: RawOperation) -> Document {
+        raw.into_document()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::Bson;
+    use {OpTime, Source};
+    use super::RawOperation;
+
+    #[test]
+    fn raw_operation_reads_optimes_of_change_events() {
+        let event = doc! {
+            "_id" => { "_data" => "826" },
+            "operationType" => "invalidate",
+            "clusterTime" => (Bson::TimeStamp((1479561394 << 32) + 1))
+        };
+        let raw = RawOperation::new(event, Source::ChangeStream);
+
+        assert_eq!(raw.optime().unwrap(), OpTime::new(1479561394, 1));
+        assert!(raw.operation().is_err());
+    }
+}
@@ -0,0 +1,214 @@
+//! The stream module is responsible for exposing an oplog as an asynchronous `Stream` of
+//! operations.
+//!
+//! As the MongoDB driver is synchronous, the oplog is read on a dedicated background thread.
+//! That thread only reads the next entry once the stream is polled for it, so the oplog (and any
+//! checkpoints it commits) never runs ahead of the consumer.
+//!
+//! Note that this does not avoid blocking an OS thread per oplog: it only moves the blocking off
+//! the executor's threads. Doing without would require an asynchronous MongoDB driver.
+
+use std::pin::Pin;
+use std::sync::mpsc as sync_mpsc;
+use std::thread;
+
+use futures::channel::mpsc;
+use futures::stream::Stream;
+use futures::task::{Context, Poll};
+
+use {Oplog, OplogBuilder, Operation, Result};
+
+/// An asynchronous stream of the operations in an oplog.
+///
+/// It implements the `futures::Stream` trait, yielding a `Result` for every entry in the same way
+/// as `Oplog::try_iter`. Dropping the stream stops reading from the oplog.
+///
+/// Each stream reads from the oplog on its own background thread, which blocks while awaiting
+/// new entries and exits once the stream is dropped and the server next returns.
+///
+/// This is returned by `OplogBuilder::build_stream` or `Oplog::into_stream` and is only available
+/// with the `async` feature.
+pub struct OplogStream {
+    /// Requests the next entry from the background thread.
+    demand: sync_mpsc::Sender<()>,
+    /// Receives entries from the background thread.
+    receiver: mpsc::UnboundedReceiver<Result<Operation>>,
+    /// Whether an entry has been requested but not yet received.
+    requested: bool,
+}
+
+impl OplogStream {
+    /// Returns a stream of the entries returned by `next`, called on a background thread.
+    ///
+    /// `next` should return `None` periodically when no entry is available so that the thread can
+    /// stop once the stream is dropped.
+    fn spawn<F>(mut next: F) -> OplogStream
+        where F: FnMut() -> Option<Result<Operation>> + Send + 'static
+    {
+        let (demand, requests) = sync_mpsc::channel();
+        let (sender, receiver) = mpsc::unbounded();
+
+        thread::spawn(move || {
+            while requests.recv().is_ok() {
+                loop {
+                    if sender.is_closed() {
+                        return;
+                    }
+
+                    if let Some(result) = next() {
+                        if sender.unbounded_send(result).is_err() {
+                            return;
+                        }
+
+                        break;
+                    }
+                }
+            }
+        });
+
+        OplogStream {
+            demand,
+            receiver,
+            requested: false,
+        }
+    }
+}
+
+impl Stream for OplogStream {
+    type Item = Result<Operation>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        if !self.requested {
+            if self.demand.send(()).is_err() {
+                return Poll::Ready(None);
+            }
+
+            self.requested = true;
+        }
+
+        let poll = Pin::new(&mut self.receiver).poll_next(cx);
+        if poll.is_ready() {
+            self.requested = false;
+        }
+
+        poll
+    }
+}
+
+impl Oplog {
+    /// Converts the `Oplog` into an asynchronous `Stream` of operations.
+    ///
+    /// This is only available with the `async` feature.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # extern crate futures;
+    /// # extern crate mongodb;
+    /// # extern crate oplog;
+    /// use futures::executor::block_on;
+    /// use futures::stream::StreamExt;
+    /// use mongodb::{Client, ThreadedClient};
+    /// use oplog::Oplog;
+    ///
+    /// # fn main() {
+    /// let client = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+    ///
+    /// if let Ok(oplog) = Oplog::new(&client) {
+    ///     let operations = block_on(oplog.into_stream().take(10).collect::<Vec<_>>());
+    /// }
+    /// # }
+    /// ```
+    pub fn into_stream(mut self) -> OplogStream {
+        OplogStream::spawn(move || self.poll_operation())
+    }
+}
+
+impl<'a> OplogBuilder<'a> {
+    /// Executes the query and builds an asynchronous `Stream` of operations.
+    ///
+    /// This is equivalent to calling `build` followed by `Oplog::into_stream` and is only
+    /// available with the `async` feature.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # extern crate futures;
+    /// # extern crate mongodb;
+    /// # extern crate oplog;
+    /// use futures::executor::block_on;
+    /// use futures::stream::StreamExt;
+    /// use mongodb::{Client, ThreadedClient};
+    /// use oplog::{OperationKind, OplogBuilder};
+    ///
+    /// # fn main() {
+    /// let client = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+    ///
+    /// if let Ok(stream) = OplogBuilder::new(&client)
+    ///                                  .kinds(&[OperationKind::Insert])
+    ///                                  .build_stream() {
+    ///     block_on(stream.for_each(|insert| {
+    ///         println!("{:?}", insert);
+    ///         futures::future::ready(())
+    ///     }));
+    /// }
+    /// # }
+    /// ```
+    pub fn build_stream(&self) -> Result<OplogStream> {
+        Ok(self.build()?.into_stream())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use futures::executor::block_on;
+    use futures::stream::StreamExt;
+
+    use {Error, OpTime, Operation};
+    use super::OplogStream;
+
+    fn noop(message: &str) -> Operation {
+        Operation::Noop {
+            id: None,
+            optime: OpTime::new(1479419535, 0),
+            timestamp: OpTime::new(1479419535, 0).to_datetime(),
+            term: None,
+            wall: None,
+            version: None,
+            transaction: None,
+            message: message.into(),
+            object: doc! { "msg" => message },
+        }
+    }
+
+    #[test]
+    fn oplog_stream_yields_entries() {
+        let mut entries = vec![Ok(noop("foo")), Err(Error::InvalidOperation), Ok(noop("bar"))]
+            .into_iter();
+        let stream = OplogStream::spawn(move || entries.next());
+
+        let results = block_on(stream.take(3).collect::<Vec<_>>());
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap(), &noop("foo"));
+        assert!(results[1].is_err());
+        assert_eq!(results[2].as_ref().unwrap(), &noop("bar"));
+    }
+
+    #[test]
+    fn oplog_stream_only_reads_on_demand() {
+        let reads = Arc::new(Mutex::new(0));
+        let counter = reads.clone();
+        let mut stream = OplogStream::spawn(move || {
+            *counter.lock().unwrap() += 1;
+            Some(Ok(noop("foo")))
+        });
+
+        block_on(stream.next());
+        block_on(stream.next());
+
+        assert_eq!(*reads.lock().unwrap(), 2);
+    }
+}
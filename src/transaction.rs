@@ -0,0 +1,507 @@
+//! The transaction module is responsible for describing the session and transaction details
+//! recorded against operations written by retryable writes and multi-document transactions
+//! (MongoDB 4.0 and later), and for reassembling transactions written across several oplog
+//! entries.
+
+use bson::Document;
+
+use operation::{linked_optime, optional};
+use {OpTime, Operation, Result};
+
+/// The session and transaction an operation was written in.
+///
+/// This is present on operations written by retryable writes and on the `applyOps`,
+/// `commitTransaction` and `abortTransaction` commands written by multi-document transactions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transaction {
+    /// The logical session identifier (`lsid`) of the session.
+    pub session_id: Document,
+    /// The transaction number (`txnNumber`) within the session.
+    pub txn_number: i64,
+    /// The statement identifier (`stmtId`) within the transaction, if any.
+    pub statement_id: Option<i32>,
+    /// The position of the previous entry written by the same transaction (`prevOpTime`), if any.
+    ///
+    /// This is `OpTime::new(0, 0)` on the first entry written by a transaction or retryable write.
+    pub prev_optime: Option<OpTime>,
+    /// The election term recorded with the position of the previous entry (`prevOpTime.t`), if
+    /// any.
+    pub prev_term: Option<i64>,
+    /// The part this entry plays in a multi-document transaction, if any.
+    ///
+    /// This is `None` for retryable writes outside of a multi-document transaction.
+    pub state: Option<TransactionState>,
+}
+
+/// The part an oplog entry plays in a multi-document transaction.
+///
+/// A transaction small enough to fit in a single oplog entry is written as one `applyOps` command
+/// which commits it. Larger transactions are written as a chain of partial `applyOps` commands
+/// linked by their `prev_optime`, ending with either an `applyOps` command that commits them or,
+/// if the transaction was prepared, one that prepares them followed later by a separate
+/// `commitTransaction` or `abortTransaction` command.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum TransactionState {
+    /// An `applyOps` command with operations that are only applied once the transaction commits.
+    Partial,
+    /// The final `applyOps` command of a prepared transaction.
+    Prepared,
+    /// An `applyOps` or `commitTransaction` command committing the transaction.
+    Committed,
+    /// An `abortTransaction` command discarding the transaction.
+    Aborted,
+}
+
+impl Transaction {
+    /// Returns the transaction for a given oplog entry, if it has one.
+    pub(crate) fn from_entry(document: &Document) -> Result<Option<Transaction>> {
+        let txn_number = match optional(document.get_i64("txnNumber"))? {
+            Some(txn_number) => txn_number,
+            None => return Ok(None),
+        };
+        let session_id = document.get_document("lsid")?;
+        let statement_id = optional(document.get_i32("stmtId"))?;
+
+        let (prev_optime, prev_term) = linked_optime(document, "prevOpTime")?;
+
+        Ok(Some(Transaction {
+            session_id: session_id.to_owned(),
+            txn_number,
+            statement_id,
+            prev_optime,
+            prev_term,
+            state: state(document)?,
+        }))
+    }
+}
+
+/// Returns the part a given oplog entry plays in a multi-document transaction, if any.
+fn state(document: &Document) -> Result<Option<TransactionState>> {
+    if document.get_str("op")? != "c" {
+        return Ok(None);
+    }
+
+    let o = document.get_document("o")?;
+
+    if o.contains_key("applyOps") {
+        if optional(o.get_bool("partialTxn"))? == Some(true) {
+            Ok(Some(TransactionState::Partial))
+        } else if optional(o.get_bool("prepare"))? == Some(true) {
+            Ok(Some(TransactionState::Prepared))
+        } else {
+            Ok(Some(TransactionState::Committed))
+        }
+    } else if o.contains_key("commitTransaction") {
+        Ok(Some(TransactionState::Committed))
+    } else if o.contains_key("abortTransaction") {
+        Ok(Some(TransactionState::Aborted))
+    } else {
+        Ok(None)
+    }
+}
+
+/// An iterator adapter that reassembles multi-document transactions written across several
+/// oplog entries.
+///
+/// Partial and prepared `applyOps` commands are held back until their transaction commits, at
+/// which point a single `Operation::ApplyOps` is yielded containing every operation in the
+/// transaction in order. It has the position, time, namespace and `Transaction` of the entry that
+/// committed it. Transactions that abort are discarded. All other operations are yielded as-is.
+///
+/// Note that a transaction that began before the first operation read will be missing its
+/// earlier operations. If none of its operations were read, its `commitTransaction` command is
+/// yielded as-is.
+///
+/// An `Oplog` built with a checkpoint store does not commit positions past the first entry of a
+/// transaction still held back, so no operations are lost when resuming part-way through one.
+///
+/// Use `TryTransactions` to reassemble transactions from the results of `Oplog::try_iter`
+/// instead.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # extern crate mongodb;
+/// # extern crate oplog;
+/// use mongodb::{Client, ThreadedClient};
+/// use oplog::{Oplog, Transactions};
+///
+/// # fn main() {
+/// let client = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+///
+/// if let Ok(oplog) = Oplog::new(&client) {
+///     for operation in Transactions::new(oplog) {
+///         // Do something with operation or complete transaction...
+///     }
+/// }
+/// # }
+/// ```
+pub struct Transactions<I> {
+    operations: I,
+    /// The operations read so far for each uncommitted transaction.
+    pending: Vec<(Document, i64, Vec<Operation>)>,
+}
+
+impl<I> Transactions<I>
+    where I: Iterator<Item = Operation>
+{
+    /// Returns a new adapter reassembling transactions from the given operations.
+    pub fn new(operations: I) -> Transactions<I> {
+        Transactions {
+            operations,
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl<I> Transactions<I> {
+    /// Removes and returns the pending operations for the given transaction, if any.
+    fn take(&mut self, transaction: &Transaction) -> Option<Vec<Operation>> {
+        let index = self.pending.iter().position(|&(ref session_id, txn_number, _)| {
+            *session_id == transaction.session_id && txn_number == transaction.txn_number
+        })?;
+
+        Some(self.pending.remove(index).2)
+    }
+
+    /// Returns the operation to yield for the given operation, if any.
+    fn process(&mut self, mut operation: Operation) -> Option<Operation> {
+        let transaction = match operation.transaction() {
+            Some(transaction) => transaction.clone(),
+            None => return Some(operation),
+        };
+
+        match transaction.state {
+            Some(TransactionState::Partial) |
+            Some(TransactionState::Prepared) => {
+                let mut pending = self.take(&transaction).unwrap_or_default();
+                if let Operation::ApplyOps { ref mut operations, .. } = operation {
+                    pending.append(operations);
+                }
+                self.pending.push((transaction.session_id, transaction.txn_number, pending));
+
+                None
+            }
+            Some(TransactionState::Committed) => {
+                match self.take(&transaction) {
+                    Some(pending) => Some(commit(operation, pending)),
+                    None => Some(operation),
+                }
+            }
+            Some(TransactionState::Aborted) => {
+                self.take(&transaction);
+
+                None
+            }
+            None => Some(operation),
+        }
+    }
+}
+
+impl<I> Iterator for Transactions<I>
+    where I: Iterator<Item = Operation>
+{
+    type Item = Operation;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let operation = self.operations.next()?;
+
+            if let Some(operation) = self.process(operation) {
+                return Some(operation);
+            }
+        }
+    }
+}
+
+/// An iterator adapter that reassembles multi-document transactions from the result of reading
+/// each entry, e.g. as returned by `Oplog::try_iter`.
+///
+/// Operations are reassembled as by `Transactions` and errors are yielded as they are read,
+/// leaving any uncommitted transactions pending.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// # extern crate mongodb;
+/// # extern crate oplog;
+/// use mongodb::{Client, ThreadedClient};
+/// use oplog::{Oplog, TryTransactions};
+///
+/// # fn main() {
+/// let client = Client::connect("localhost", 27017).expect("Failed to connect to MongoDB.");
+///
+/// if let Ok(mut oplog) = Oplog::new(&client) {
+///     for result in TryTransactions::new(oplog.try_iter()) {
+///         match result {
+///             Ok(operation) => println!("{}", operation),
+///             Err(err) => eprintln!("Error reading oplog: {}", err),
+///         }
+///     }
+/// }
+/// # }
+/// ```
+pub struct TryTransactions<I> {
+    transactions: Transactions<I>,
+}
+
+impl<I> TryTransactions<I>
+    where I: Iterator<Item = Result<Operation>>
+{
+    /// Returns a new adapter reassembling transactions from the given results.
+    pub fn new(results: I) -> TryTransactions<I> {
+        TryTransactions {
+            transactions: Transactions {
+                operations: results,
+                pending: Vec::new(),
+            },
+        }
+    }
+}
+
+impl<I> Iterator for TryTransactions<I>
+    where I: Iterator<Item = Result<Operation>>
+{
+    type Item = Result<Operation>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let operation = match self.transactions.operations.next()? {
+                Ok(operation) => operation,
+                Err(err) => return Some(Err(err)),
+            };
+
+            if let Some(operation) = self.transactions.process(operation) {
+                return Some(Ok(operation));
+            }
+        }
+    }
+}
+
+/// Returns an `applyOps` command of the given pending operations followed by those in the given
+/// committing entry.
+fn commit(mut entry: Operation, mut pending: Vec<Operation>) -> Operation {
+    if let Operation::ApplyOps { ref mut operations, .. } = entry {
+        pending.append(operations);
+        *operations = pending;
+
+        return entry;
+    }
+
+    match entry {
+        Operation::Command { id,
+                             optime,
+                             timestamp,
+                             term,
+                             wall,
+                             version,
+                             transaction,
+                             namespace,
+                             uuid,
+                             .. } => {
+            Operation::ApplyOps {
+                id,
+                optime,
+                timestamp,
+                term,
+                wall,
+                version,
+                transaction,
+                namespace,
+                uuid,
+                operations: pending,
+            }
+        }
+        entry => entry,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::{Bson, Document};
+    use {Error, OpTime, Operation};
+    use super::{Transaction, TransactionState, Transactions, TryTransactions};
+
+    fn insert(id: i32) -> Operation {
+        Operation::Insert {
+            id: None,
+            optime: OpTime::new(1567578725, 0),
+            timestamp: OpTime::new(1567578725, 0).to_datetime(),
+            term: None,
+            wall: None,
+            version: None,
+            transaction: None,
+            namespace: "foo.bar".into(),
+            uuid: None,
+            document: doc! { "_id" => id },
+        }
+    }
+
+    fn transaction(txn_number: i64, state: TransactionState) -> Option<Transaction> {
+        Some(Transaction {
+            session_id: doc! { "id" => 1 },
+            txn_number,
+            statement_id: None,
+            prev_optime: None,
+            prev_term: None,
+            state: Some(state),
+        })
+    }
+
+    fn apply_ops(txn_number: i64,
+                 state: TransactionState,
+                 operations: Vec<Operation>)
+                 -> Operation {
+        Operation::ApplyOps {
+            id: None,
+            optime: OpTime::new(1567578725, 0),
+            timestamp: OpTime::new(1567578725, 0).to_datetime(),
+            term: None,
+            wall: None,
+            version: None,
+            transaction: transaction(txn_number, state),
+            namespace: "admin.$cmd".into(),
+            uuid: None,
+            operations,
+        }
+    }
+
+    fn command(txn_number: i64, state: TransactionState, command: Document) -> Operation {
+        Operation::Command {
+            id: None,
+            optime: OpTime::new(1567578725, 0),
+            timestamp: OpTime::new(1567578725, 0).to_datetime(),
+            term: None,
+            wall: None,
+            version: None,
+            transaction: transaction(txn_number, state),
+            namespace: "admin.$cmd".into(),
+            uuid: None,
+            command,
+        }
+    }
+
+    #[test]
+    fn transaction_ignores_entries_without_transactions() {
+        let doc = doc! {
+            "ts" => (Bson::TimeStamp(1479561394 << 32)),
+            "op" => "i",
+            "ns" => "foo.bar",
+            "o" => { "foo" => "bar" }
+        };
+
+        assert_eq!(Transaction::from_entry(&doc).unwrap(), None);
+    }
+
+    #[test]
+    fn transaction_converts_retryable_writes() {
+        let doc = doc! {
+            "ts" => (Bson::TimeStamp(1567578725 << 32)),
+            "op" => "i",
+            "ns" => "foo.bar",
+            "o" => { "foo" => "bar" },
+            "lsid" => { "id" => 1 },
+            "txnNumber" => 3i64,
+            "stmtId" => 0,
+            "prevOpTime" => { "ts" => (Bson::TimeStamp(0)), "t" => (-1i64) }
+        };
+
+        assert_eq!(Transaction::from_entry(&doc).unwrap(),
+                   Some(Transaction {
+                       session_id: doc! { "id" => 1 },
+                       txn_number: 3,
+                       statement_id: Some(0),
+                       prev_optime: Some(OpTime::new(0, 0)),
+                       prev_term: Some(-1),
+                       state: None,
+                   }));
+    }
+
+    #[test]
+    fn transaction_converts_partial_apply_ops() {
+        let doc = doc! {
+            "ts" => (Bson::TimeStamp((1567578725 << 32) + 2)),
+            "op" => "c",
+            "ns" => "admin.$cmd",
+            "o" => { "applyOps" => [], "partialTxn" => true },
+            "lsid" => { "id" => 1 },
+            "txnNumber" => 3i64,
+            "prevOpTime" => { "ts" => (Bson::TimeStamp((1567578725 << 32) + 1)), "t" => 1i64 }
+        };
+
+        let transaction = Transaction::from_entry(&doc).unwrap().unwrap();
+
+        assert_eq!(transaction.prev_optime, Some(OpTime::new(1567578725, 1)));
+        assert_eq!(transaction.state, Some(TransactionState::Partial));
+    }
+
+    #[test]
+    fn transaction_converts_commits_and_aborts() {
+        let commit = doc! {
+            "ts" => (Bson::TimeStamp(1567578725 << 32)),
+            "op" => "c",
+            "ns" => "admin.$cmd",
+            "o" => { "commitTransaction" => 1 },
+            "lsid" => { "id" => 1 },
+            "txnNumber" => 3i64
+        };
+        let abort = doc! {
+            "ts" => (Bson::TimeStamp(1567578725 << 32)),
+            "op" => "c",
+            "ns" => "admin.$cmd",
+            "o" => { "abortTransaction" => 1 },
+            "lsid" => { "id" => 1 },
+            "txnNumber" => 3i64
+        };
+
+        assert_eq!(Transaction::from_entry(&commit).unwrap().unwrap().state,
+                   Some(TransactionState::Committed));
+        assert_eq!(Transaction::from_entry(&abort).unwrap().unwrap().state,
+                   Some(TransactionState::Aborted));
+    }
+
+    #[test]
+    fn transactions_reassembles_partial_transactions() {
+        let operations = vec![apply_ops(1, TransactionState::Partial, vec![insert(1)]),
+                              insert(2),
+                              apply_ops(1, TransactionState::Committed, vec![insert(3)])];
+
+        let operations = Transactions::new(operations.into_iter()).collect::<Vec<_>>();
+
+        assert_eq!(operations,
+                   vec![insert(2),
+                        apply_ops(1, TransactionState::Committed, vec![insert(1), insert(3)])]);
+    }
+
+    #[test]
+    fn transactions_commits_prepared_transactions() {
+        let abort = doc! { "abortTransaction" => 1 };
+        let commit = doc! { "commitTransaction" => 1 };
+        let operations = vec![apply_ops(1, TransactionState::Prepared, vec![insert(1)]),
+                              apply_ops(2, TransactionState::Prepared, vec![insert(2)]),
+                              command(2, TransactionState::Aborted, abort),
+                              command(1, TransactionState::Committed, commit)];
+
+        let operations = Transactions::new(operations.into_iter()).collect::<Vec<_>>();
+
+        assert_eq!(operations,
+                   vec![apply_ops(1, TransactionState::Committed, vec![insert(1)])]);
+    }
+
+    #[test]
+    fn try_transactions_yields_errors() {
+        let results = vec![Ok(apply_ops(1, TransactionState::Partial, vec![insert(1)])),
+                           Err(Error::InvalidOperation),
+                           Ok(apply_ops(1, TransactionState::Committed, vec![insert(2)]))];
+
+        let mut results = TryTransactions::new(results.into_iter());
+
+        match results.next() {
+            Some(Err(Error::InvalidOperation)) => {}
+            _ => panic!("Expected invalid operation."),
+        }
+        assert_eq!(results.next().unwrap().unwrap(),
+                   apply_ops(1, TransactionState::Committed, vec![insert(1), insert(2)]));
+        assert!(results.next().is_none());
+    }
+}
@@ -0,0 +1,828 @@
+//! The update module is responsible for describing the changes made by an update operation,
+//! regardless of the format MongoDB recorded them in.
+//!
+//! Before MongoDB 5.0, updates are recorded as either a replacement document or `$set` and
+//! `$unset` modifiers. From MongoDB 5.0, they may instead be recorded with `$v: 2` as a `diff` of
+//! the document.
+//!
+//! Updates can also be applied to a previous version of a document to compute the new version
+//! without reading it from the database.
+
+use std::mem;
+
+use bson::{Bson, Document};
+
+use {Error, Result};
+
+/// The largest array MongoDB will pad with nulls to set an element beyond its end.
+const MAX_PADDED_LENGTH: usize = 1_500_000;
+
+/// The changes made to a document by an update operation.
+#[derive(Clone, Debug, PartialEq)]
+pub enum UpdateDescription {
+    /// The replacement of the entire document.
+    Replacement(Document),
+    /// Changes to individual fields of the document.
+    Delta {
+        /// The new value of each updated or inserted field by its dotted path (e.g. `a.b.0`).
+        updated_fields: Document,
+        /// The dotted path of each removed field.
+        removed_fields: Vec<String>,
+        /// The dotted path and new length of each truncated array.
+        truncated_arrays: Vec<(String, i64)>,
+    },
+}
+
+impl UpdateDescription {
+    /// Returns the description of the given update as recorded in the `o` field of an update
+    /// operation.
+    ///
+    /// Returns an error if the update uses modifiers other than `$set`, `$unset` and the `$push` of
+    /// no elements with `$slice` that truncates an array (as returned by `to_modifiers`), or its
+    /// diff is malformed.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[macro_use]
+    /// # extern crate bson;
+    /// # extern crate oplog;
+    /// use oplog::UpdateDescription;
+    ///
+    /// # fn main() {
+    /// let update = doc! {
+    ///     "$v" => 2,
+    ///     "diff" => {
+    ///         "u" => { "name" => "Alice" },
+    ///         "sprofile" => {
+    ///             "d" => { "age" => false }
+    ///         }
+    ///     }
+    /// };
+    ///
+    /// assert_eq!(UpdateDescription::new(&update).unwrap(),
+    ///            UpdateDescription::Delta {
+    ///                updated_fields: doc! { "name" => "Alice" },
+    ///                removed_fields: vec!["profile.age".into()],
+    ///                truncated_arrays: vec![],
+    ///            });
+    /// # }
+    /// ```
+    pub fn new(update: &Document) -> Result<UpdateDescription> {
+        let mut updated_fields = Document::new();
+        let mut removed_fields = Vec::new();
+        let mut truncated_arrays = Vec::new();
+
+        match update.get("$v") {
+            Some(&Bson::I32(2)) | Some(&Bson::I64(2)) => {
+                diff(update.get_document("diff")?,
+                     "",
+                     &mut updated_fields,
+                     &mut removed_fields,
+                     &mut truncated_arrays)?;
+            }
+            _ => {
+                if !update.keys().any(|key| key.starts_with('$')) {
+                    return Ok(UpdateDescription::Replacement(update.clone()));
+                }
+
+                for (key, value) in update {
+                    match key.as_str() {
+                        "$v" => {}
+                        "$set" => {
+                            for (field, value) in as_document(value)? {
+                                updated_fields.insert(field.clone(), value.clone());
+                            }
+                        }
+                        "$unset" => removed_fields.extend(as_document(value)?.keys().cloned()),
+                        "$push" => {
+                            for (field, value) in as_document(value)? {
+                                truncated_arrays.push((field.clone(), truncation(value)?));
+                            }
+                        }
+                        _ => return Err(Error::InvalidOperation),
+                    }
+                }
+            }
+        }
+
+        Ok(UpdateDescription::Delta {
+            updated_fields,
+            removed_fields,
+            truncated_arrays,
+        })
+    }
+
+    /// Returns the update modifiers that make these changes, in the order they must be applied,
+    /// or `None` for a replacement.
+    ///
+    /// Truncated arrays are resized with a `$push` before any fields are set with `$set` or
+    /// removed with `$unset`.
+    pub fn to_modifiers(&self) -> Option<Vec<Document>> {
+        match *self {
+            UpdateDescription::Replacement(_) => None,
+            UpdateDescription::Delta { ref updated_fields,
+                                       ref removed_fields,
+                                       ref truncated_arrays } => {
+                let mut modifiers = Vec::new();
+
+                if !truncated_arrays.is_empty() {
+                    let mut push = Document::new();
+                    for &(ref field, length) in truncated_arrays {
+                        push.insert(field.clone(), doc! { "$each" => [], "$slice" => length });
+                    }
+                    modifiers.push(doc! { "$push" => push });
+                }
+
+                let mut update = Document::new();
+                if !updated_fields.is_empty() {
+                    update.insert("$set", updated_fields.clone());
+                }
+                if !removed_fields.is_empty() {
+                    let mut unset = Document::new();
+                    for field in removed_fields {
+                        unset.insert(field.clone(), true);
+                    }
+                    update.insert("$unset", unset);
+                }
+                if !update.is_empty() {
+                    modifiers.push(update);
+                }
+
+                Some(modifiers)
+            }
+        }
+    }
+
+    /// Returns the given document with these changes applied.
+    ///
+    /// A replacement keeps the `_id` of the given document if it does not have its own.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[macro_use]
+    /// # extern crate bson;
+    /// # extern crate oplog;
+    /// use oplog::UpdateDescription;
+    ///
+    /// # fn main() {
+    /// let description = UpdateDescription::Delta {
+    ///     updated_fields: doc! { "tags.1" => "b" },
+    ///     removed_fields: vec!["age".into()],
+    ///     truncated_arrays: vec![("tags".into(), 1)],
+    /// };
+    /// let document = doc! { "_id" => 1, "age" => 42, "tags" => ["a", "x", "y"] };
+    ///
+    /// assert_eq!(description.apply_to(&document).unwrap(),
+    ///            doc! { "_id" => 1, "tags" => ["a", "b"] });
+    /// # }
+    /// ```
+    pub fn apply_to(&self, document: &Document) -> Result<Document> {
+        match *self {
+            UpdateDescription::Replacement(ref replacement) => {
+                match document.get("_id") {
+                    Some(id) if !replacement.contains_key("_id") => {
+                        let mut result = doc! { "_id" => (id.clone()) };
+                        for (key, value) in replacement {
+                            result.insert(key.clone(), value.clone());
+                        }
+
+                        Ok(result)
+                    }
+                    _ => Ok(replacement.clone()),
+                }
+            }
+            UpdateDescription::Delta { ref updated_fields,
+                                       ref removed_fields,
+                                       ref truncated_arrays } => {
+                let mut root = Bson::Document(document.clone());
+
+                for &(ref field, length) in truncated_arrays {
+                    match *entry(&mut root, field, Bson::Null)? {
+                        Bson::Array(ref mut array) => array.truncate(length as usize),
+                        _ => return Err(Error::InvalidOperation),
+                    }
+                }
+                for (field, value) in updated_fields {
+                    *entry(&mut root, field, Bson::Null)? = value.clone();
+                }
+                for field in removed_fields {
+                    remove(&mut root, field, false);
+                }
+
+                into_document(root)
+            }
+        }
+    }
+}
+
+/// Collects the changes described by a `$v: 2` diff of the document or array at the given path.
+///
+/// A diff of a document contains fields to update (`u`), insert (`i`) or delete (`d`) and
+/// sub-diffs of nested documents or arrays (prefixed with `s`). A diff of an array (marked with
+/// `a: true`) contains elements to update (prefixed with `u`), sub-diffs of elements (prefixed
+/// with `s`) and an optional new length (`l`).
+fn diff(diff: &Document,
+        path: &str,
+        updated_fields: &mut Document,
+        removed_fields: &mut Vec<String>,
+        truncated_arrays: &mut Vec<(String, i64)>)
+        -> Result<()> {
+    let is_array = diff.get_bool("a").unwrap_or(false);
+
+    for (key, value) in diff {
+        match (is_array, key.as_str()) {
+            (_, "a") => {}
+            (false, "u") | (false, "i") => {
+                for (field, value) in as_document(value)? {
+                    updated_fields.insert(join(path, field), value.clone());
+                }
+            }
+            (false, "d") => {
+                removed_fields.extend(as_document(value)?.keys().map(|field| join(path, field)));
+            }
+            (true, "l") => {
+                let length = match *value {
+                    Bson::I32(length) => i64::from(length),
+                    Bson::I64(length) => length,
+                    _ => return Err(Error::InvalidOperation),
+                };
+                if length < 0 {
+                    return Err(Error::InvalidOperation);
+                }
+                truncated_arrays.push((path.into(), length));
+            }
+            (true, _) if key.starts_with('u') => {
+                updated_fields.insert(join(path, &key[1..]), value.clone());
+            }
+            (_, _) if key.starts_with('s') => {
+                self::diff(as_document(value)?,
+                           &join(path, &key[1..]),
+                           updated_fields,
+                           removed_fields,
+                           truncated_arrays)?;
+            }
+            _ => return Err(Error::InvalidOperation),
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the new length of an array truncated by the given `$push` of no elements with `$slice`.
+fn truncation(push: &Bson) -> Result<i64> {
+    let push = as_document(push)?;
+    let length = match push.get("$slice") {
+        Some(&Bson::I32(length)) => i64::from(length),
+        Some(&Bson::I64(length)) => length,
+        _ => return Err(Error::InvalidOperation),
+    };
+
+    match push.get_array("$each") {
+        Ok(each) if each.is_empty() && push.len() == 2 && length >= 0 => Ok(length),
+        _ => Err(Error::InvalidOperation),
+    }
+}
+
+/// Returns the given BSON value as a document.
+fn as_document(value: &Bson) -> Result<&Document> {
+    match *value {
+        Bson::Document(ref document) => Ok(document),
+        _ => Err(Error::InvalidOperation),
+    }
+}
+
+/// Returns the dotted path to a field of the given path.
+fn join(path: &str, field: &str) -> String {
+    if path.is_empty() {
+        field.into()
+    } else {
+        format!("{}.{}", path, field)
+    }
+}
+
+/// Returns the given document with the given update applied, where the update is the `o` field
+/// of an update operation.
+///
+/// As well as the `$set` and `$unset` modifiers recorded in the oplog, this supports `$inc`,
+/// `$push` (with `$each`, `$position` and `$slice`), `$pull` (of equal values, matching documents
+/// or values `$in` an array), `$addToSet` (with `$each`) and `$rename`.
+pub(crate) fn apply(document: &Document, update: &Document) -> Result<Document> {
+    if let Some(&Bson::I32(2)) | Some(&Bson::I64(2)) = update.get("$v") {
+        return UpdateDescription::new(update)?.apply_to(document);
+    }
+    if !update.keys().any(|key| key.starts_with('$')) {
+        return UpdateDescription::Replacement(update.clone()).apply_to(document);
+    }
+
+    let mut root = Bson::Document(document.clone());
+
+    for (modifier, fields) in update {
+        if modifier == "$v" {
+            continue;
+        }
+
+        for (field, value) in as_document(fields)? {
+            match modifier.as_str() {
+                "$set" => *entry(&mut root, field, Bson::Null)? = value.clone(),
+                "$unset" => {
+                    remove(&mut root, field, false);
+                }
+                "$inc" => {
+                    let current = entry(&mut root, field, Bson::I32(0))?;
+                    *current = add(current, value)?;
+                }
+                "$push" => push(array(entry(&mut root, field, Bson::Array(Vec::new()))?)?, value)?,
+                "$addToSet" => {
+                    let array = array(entry(&mut root, field, Bson::Array(Vec::new()))?)?;
+                    let values = match *value {
+                        Bson::Document(ref each) if each.contains_key("$each") => {
+                            each.get_array("$each")?.clone()
+                        }
+                        _ => vec![value.clone()],
+                    };
+
+                    for value in values {
+                        if !array.contains(&value) {
+                            array.push(value);
+                        }
+                    }
+                }
+                "$pull" => {
+                    let array = match get_mut(&mut root, field) {
+                        Some(value) => array(value)?,
+                        None => continue,
+                    };
+                    let mut retained = Vec::with_capacity(array.len());
+
+                    for element in array.drain(..) {
+                        if !matches(&element, value)? {
+                            retained.push(element);
+                        }
+                    }
+                    *array = retained;
+                }
+                "$rename" => {
+                    let target = match *value {
+                        Bson::String(ref target) => target,
+                        _ => return Err(Error::InvalidOperation),
+                    };
+
+                    if let Some(value) = remove(&mut root, field, true) {
+                        *entry(&mut root, target, Bson::Null)? = value;
+                    }
+                }
+                _ => return Err(Error::InvalidOperation),
+            }
+        }
+    }
+
+    into_document(root)
+}
+
+/// Returns the value at the given dotted path, creating it with the given default and any
+/// missing documents along the way as needed.
+///
+/// Numeric parts of the path index into arrays, padding them with nulls if necessary, though
+/// never beyond `MAX_PADDED_LENGTH` elements. As with MongoDB, a path through any other value,
+/// including null, is an error.
+fn entry<'a>(value: &'a mut Bson, path: &str, default: Bson) -> Result<&'a mut Bson> {
+    let mut value = value;
+    let mut fields = path.split('.').peekable();
+
+    while let Some(field) = fields.next() {
+        let missing = if fields.peek().is_some() {
+            Bson::Document(Document::new())
+        } else {
+            default.clone()
+        };
+
+        value = match *value {
+            Bson::Document(ref mut document) => document.entry(field.into()).or_insert(missing),
+            Bson::Array(ref mut array) => {
+                let index = field.parse::<usize>().map_err(|_| Error::InvalidOperation)?;
+                if array.len() <= index {
+                    if index >= MAX_PADDED_LENGTH {
+                        return Err(Error::InvalidOperation);
+                    }
+
+                    array.resize(index, Bson::Null);
+                    array.push(missing);
+                }
+
+                &mut array[index]
+            }
+            _ => return Err(Error::InvalidOperation),
+        };
+    }
+
+    Ok(value)
+}
+
+/// Returns the value at the given dotted path, if any.
+fn get_mut<'a>(value: &'a mut Bson, path: &str) -> Option<&'a mut Bson> {
+    let mut value = value;
+
+    for field in path.split('.') {
+        value = match *value {
+            Bson::Document(ref mut document) => document.get_mut(field)?,
+            Bson::Array(ref mut array) => array.get_mut(field.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+
+    Some(value)
+}
+
+/// Removes the value at the given dotted path, if any.
+///
+/// Elements of arrays are replaced with null as by `$unset` unless `shift` is given, in which
+/// case they are removed from the array.
+fn remove(value: &mut Bson, path: &str, shift: bool) -> Option<Bson> {
+    let (parent, field) = match path.rfind('.') {
+        Some(index) => (Some(&path[..index]), &path[index + 1..]),
+        None => (None, path),
+    };
+
+    let mut value = value;
+    if let Some(parent) = parent {
+        for part in parent.split('.') {
+            value = match *value {
+                Bson::Document(ref mut document) => document.get_mut(part)?,
+                Bson::Array(ref mut array) => array.get_mut(part.parse::<usize>().ok()?)?,
+                _ => return None,
+            };
+        }
+    }
+
+    match *value {
+        Bson::Document(ref mut document) => document.remove(field),
+        Bson::Array(ref mut array) => {
+            let index = field.parse::<usize>().ok()?;
+            if index >= array.len() {
+                None
+            } else if shift {
+                Some(array.remove(index))
+            } else {
+                Some(mem::replace(&mut array[index], Bson::Null))
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Returns the given value as an array.
+fn array(value: &mut Bson) -> Result<&mut Vec<Bson>> {
+    match *value {
+        Bson::Array(ref mut array) => Ok(array),
+        _ => Err(Error::InvalidOperation),
+    }
+}
+
+/// Returns the sum of the given numbers, widening the result as needed.
+fn add(current: &Bson, increment: &Bson) -> Result<Bson> {
+    match (current, increment) {
+        (&Bson::I32(a), &Bson::I32(b)) => {
+            Ok(a.checked_add(b).map_or_else(|| Bson::I64(i64::from(a) + i64::from(b)), Bson::I32))
+        }
+        (&Bson::I32(a), &Bson::I64(b)) => long(i64::from(a).checked_add(b)),
+        (&Bson::I64(a), &Bson::I32(b)) => long(a.checked_add(i64::from(b))),
+        (&Bson::I64(a), &Bson::I64(b)) => long(a.checked_add(b)),
+        (a, b) => Ok(Bson::FloatingPoint(float(a)? + float(b)?)),
+    }
+}
+
+/// Returns the result of a 64-bit integer addition, failing if it overflowed.
+fn long(sum: Option<i64>) -> Result<Bson> {
+    sum.map(Bson::I64).ok_or(Error::InvalidOperation)
+}
+
+/// Returns the given number as a float.
+fn float(value: &Bson) -> Result<f64> {
+    match *value {
+        Bson::I32(value) => Ok(f64::from(value)),
+        Bson::I64(value) => Ok(value as f64),
+        Bson::FloatingPoint(value) => Ok(value),
+        _ => Err(Error::InvalidOperation),
+    }
+}
+
+/// Pushes the given value or values given by `$each` onto the given array.
+fn push(array: &mut Vec<Bson>, value: &Bson) -> Result<()> {
+    let modifiers = match *value {
+        Bson::Document(ref modifiers) if modifiers.contains_key("$each") => modifiers,
+        _ => {
+            array.push(value.clone());
+            return Ok(());
+        }
+    };
+
+    let mut position = array.len();
+    let mut slice = None;
+
+    for (key, value) in modifiers {
+        match key.as_str() {
+            "$each" => {}
+            "$position" => {
+                let index = float(value)? as i64;
+                let len = array.len() as i64;
+                position = if index < 0 { (len + index).max(0) } else { index.min(len) } as usize;
+            }
+            "$slice" => slice = Some(float(value)? as i64),
+            _ => return Err(Error::InvalidOperation),
+        }
+    }
+
+    let each = modifiers.get_array("$each")?;
+    let tail = array.split_off(position);
+    array.extend(each.iter().cloned());
+    array.extend(tail);
+
+    match slice {
+        Some(slice) if slice >= 0 => array.truncate(slice as usize),
+        Some(slice) => {
+            let skip = array.len().saturating_sub(slice.unsigned_abs() as usize);
+            array.drain(..skip);
+        }
+        None => {}
+    }
+
+    Ok(())
+}
+
+/// Returns whether the given array element matches the given `$pull` condition.
+fn matches(element: &Bson, condition: &Bson) -> Result<bool> {
+    let condition = match *condition {
+        Bson::Document(ref condition) => condition,
+        _ => return Ok(element == condition),
+    };
+
+    if let Some(values) = condition.get("$in") {
+        match *values {
+            Bson::Array(ref values) => return Ok(values.contains(element)),
+            _ => return Err(Error::InvalidOperation),
+        }
+    }
+    if condition.keys().any(|key| key.starts_with('$')) {
+        return Err(Error::InvalidOperation);
+    }
+
+    match *element {
+        Bson::Document(ref element) => {
+            Ok(condition.iter().all(|(key, value)| element.get(key) == Some(value)))
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Returns the given value as a document.
+fn into_document(value: Bson) -> Result<Document> {
+    match value {
+        Bson::Document(document) => Ok(document),
+        _ => Err(Error::InvalidOperation),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::Bson;
+    use Error;
+    use super::{apply, UpdateDescription};
+
+    #[test]
+    fn update_description_decodes_legacy_updates() {
+        let update = doc! { "$v" => 1, "$set" => { "a.b" => 1 }, "$unset" => { "c" => true } };
+
+        assert_eq!(UpdateDescription::new(&update).unwrap(),
+                   UpdateDescription::Delta {
+                       updated_fields: doc! { "a.b" => 1 },
+                       removed_fields: vec!["c".into()],
+                       truncated_arrays: vec![],
+                   });
+
+        let truncation = doc! {
+            "$push" => { "a" => { "$each" => [], "$slice" => 2 } },
+            "$set" => { "a.1" => "x" }
+        };
+
+        assert_eq!(UpdateDescription::new(&truncation).unwrap(),
+                   UpdateDescription::Delta {
+                       updated_fields: doc! { "a.1" => "x" },
+                       removed_fields: vec![],
+                       truncated_arrays: vec![("a".into(), 2)],
+                   });
+        assert_eq!(UpdateDescription::new(&doc! { "_id" => 1, "foo" => "bar" }).unwrap(),
+                   UpdateDescription::Replacement(doc! { "_id" => 1, "foo" => "bar" }));
+
+        match UpdateDescription::new(&doc! { "$inc" => { "a" => 1 } }) {
+            Err(Error::InvalidOperation) => {}
+            _ => panic!("Expected invalid operation."),
+        }
+        match UpdateDescription::new(&doc! { "$push" => { "a" => 1 } }) {
+            Err(Error::InvalidOperation) => {}
+            _ => panic!("Expected invalid operation."),
+        }
+    }
+
+    #[test]
+    fn update_description_decodes_diffs() {
+        let update = doc! {
+            "$v" => 2,
+            "diff" => {
+                "u" => { "foo" => "bar" },
+                "d" => { "baz" => false },
+                "sa" => {
+                    "i" => { "b" => 1 },
+                    "sc" => {
+                        "a" => true,
+                        "l" => 2,
+                        "u1" => "x",
+                        "s0" => { "u" => { "d" => 1 } }
+                    }
+                }
+            }
+        };
+
+        assert_eq!(UpdateDescription::new(&update).unwrap(),
+                   UpdateDescription::Delta {
+                       updated_fields: doc! {
+                           "foo" => "bar",
+                           "a.b" => 1,
+                           "a.c.1" => "x",
+                           "a.c.0.d" => 1
+                       },
+                       removed_fields: vec!["baz".into()],
+                       truncated_arrays: vec![("a.c".into(), 2)],
+                   });
+    }
+
+    #[test]
+    fn update_description_converts_to_modifiers() {
+        let description = UpdateDescription::Delta {
+            updated_fields: doc! { "a.1" => "x" },
+            removed_fields: vec!["b".into()],
+            truncated_arrays: vec![("a".into(), 2)],
+        };
+
+        assert_eq!(description.to_modifiers(),
+                   Some(vec![doc! { "$push" => { "a" => { "$each" => [], "$slice" => 2i64 } } },
+                             doc! { "$set" => { "a.1" => "x" }, "$unset" => { "b" => true } }]));
+        assert_eq!(UpdateDescription::Replacement(doc! { "_id" => 1 }).to_modifiers(), None);
+    }
+
+    #[test]
+    fn apply_sets_and_unsets_dotted_paths() {
+        let document = doc! { "_id" => 1, "a" => { "b" => 1, "c" => 2 }, "d" => [1, 2] };
+        let update = doc! {
+            "$v" => 1,
+            "$set" => { "a.b" => 3, "d.3" => 4, "e.f" => true },
+            "$unset" => { "a.c" => true, "d.0" => true }
+        };
+
+        assert_eq!(apply(&document, &update).unwrap(),
+                   doc! {
+                       "_id" => 1,
+                       "a" => { "b" => 3 },
+                       "d" => [(Bson::Null), 2, (Bson::Null), 4],
+                       "e" => { "f" => true }
+                   });
+    }
+
+    #[test]
+    fn apply_modifies_numbers_and_arrays() {
+        let document = doc! {
+            "_id" => 1,
+            "count" => 2147483647,
+            "tags" => ["a", "b"],
+            "scores" => [1, 2, 3, 2],
+            "items" => [{ "sku" => "x", "qty" => 1 }, { "sku" => "y", "qty" => 2 }],
+            "old" => "name"
+        };
+        let update = doc! {
+            "$inc" => { "count" => 1, "total" => 1.5 },
+            "$push" => { "tags" => { "$each" => ["c", "d"], "$position" => 0, "$slice" => 3 } },
+            "$pull" => { "scores" => 2, "items" => { "sku" => "x" } },
+            "$addToSet" => { "scores" => { "$each" => [1, 4] } },
+            "$rename" => { "old" => "new" }
+        };
+
+        assert_eq!(apply(&document, &update).unwrap(),
+                   doc! {
+                       "_id" => 1,
+                       "count" => 2147483648i64,
+                       "tags" => ["c", "d", "a"],
+                       "scores" => [1, 3, 4],
+                       "items" => [{ "sku" => "y", "qty" => 2 }],
+                       "total" => 1.5,
+                       "new" => "name"
+                   });
+    }
+
+    #[test]
+    fn apply_applies_diffs_and_replacements() {
+        let document = doc! { "_id" => 1, "a" => { "b" => [1, 2, 3] }, "c" => 1 };
+        let diff = doc! {
+            "$v" => 2,
+            "diff" => {
+                "d" => { "c" => false },
+                "sa" => {
+                    "i" => { "d" => 1 },
+                    "sb" => { "a" => true, "l" => 2, "u1" => 5 }
+                }
+            }
+        };
+
+        assert_eq!(apply(&document, &diff).unwrap(),
+                   doc! { "_id" => 1, "a" => { "b" => [1, 5], "d" => 1 } });
+        assert_eq!(apply(&document, &doc! { "e" => 1 }).unwrap(),
+                   doc! { "_id" => 1, "e" => 1 });
+    }
+
+    #[test]
+    fn apply_returns_errors_for_invalid_updates() {
+        let document = doc! { "_id" => 1, "a" => "b" };
+
+        match apply(&document, &doc! { "$inc" => { "a" => 1 } }) {
+            Err(Error::InvalidOperation) => {}
+            _ => panic!("Expected invalid operation."),
+        }
+        match apply(&document, &doc! { "$set" => { "a.b" => 1 } }) {
+            Err(Error::InvalidOperation) => {}
+            _ => panic!("Expected invalid operation."),
+        }
+    }
+
+    #[test]
+    fn apply_returns_errors_for_overflows() {
+        let document = doc! { "_id" => 1, "a" => (i64::MAX), "b" => (i32::MAX) };
+
+        assert_eq!(apply(&document, &doc! { "$inc" => { "b" => 1 } }).unwrap(),
+                   doc! { "_id" => 1, "a" => (i64::MAX), "b" => (i64::from(i32::MAX) + 1) });
+
+        for update in &[doc! { "$inc" => { "a" => 1 } },
+                        doc! { "$inc" => { "a" => 1i64 } },
+                        doc! { "$inc" => { "b" => (i64::MAX) } }] {
+            match apply(&document, update) {
+                Err(Error::InvalidOperation) => {}
+                _ => panic!("Expected invalid operation."),
+            }
+        }
+    }
+
+    #[test]
+    fn apply_returns_errors_for_huge_array_indexes() {
+        let document = doc! { "_id" => 1, "a" => [1] };
+
+        match apply(&document, &doc! { "$set" => { "a.18446744073709551615" => 1 } }) {
+            Err(Error::InvalidOperation) => {}
+            _ => panic!("Expected invalid operation."),
+        }
+        match apply(&document, &doc! { "$set" => { "a.1500000" => 1 } }) {
+            Err(Error::InvalidOperation) => {}
+            _ => panic!("Expected invalid operation."),
+        }
+        assert_eq!(apply(&document, &doc! { "$set" => { "a.2" => 1 } }).unwrap(),
+                   doc! { "_id" => 1, "a" => [1, (Bson::Null), 1] });
+    }
+
+    #[test]
+    fn apply_pulls_from_missing_fields_without_creating_them() {
+        let document = doc! { "_id" => 1, "a" => [1, 2] };
+
+        assert_eq!(apply(&document, &doc! { "$pull" => { "b" => 1, "c.d" => 1 } }).unwrap(),
+                   document);
+    }
+
+    #[test]
+    fn apply_returns_errors_for_null_parents() {
+        let document = doc! { "_id" => 1, "a" => (Bson::Null) };
+
+        match apply(&document, &doc! { "$set" => { "a.b" => 1 } }) {
+            Err(Error::InvalidOperation) => {}
+            _ => panic!("Expected invalid operation."),
+        }
+    }
+
+    #[test]
+    fn apply_returns_errors_for_incrementing_null() {
+        let document = doc! { "_id" => 1, "a" => (Bson::Null) };
+
+        match apply(&document, &doc! { "$inc" => { "a" => 1 } }) {
+            Err(Error::InvalidOperation) => {}
+            _ => panic!("Expected invalid operation."),
+        }
+        assert_eq!(apply(&document, &doc! { "$inc" => { "b" => 1i64 } }).unwrap(),
+                   doc! { "_id" => 1, "a" => (Bson::Null), "b" => 1i64 });
+    }
+
+    #[test]
+    fn update_description_rejects_negative_array_lengths() {
+        let update = doc! { "$v" => 2, "diff" => { "sa" => { "a" => true, "l" => -1 } } };
+
+        match UpdateDescription::new(&update) {
+            Err(Error::InvalidOperation) => {}
+            _ => panic!("Expected invalid operation."),
+        }
+    }
+}